@@ -0,0 +1,19 @@
+#![no_main]
+
+use std::path::Path;
+
+use bmsjoin::{BMSFile, Line};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let bms = BMSFile::from_bytes(Path::new("fuzz.bms"), data);
+
+    // Untouched lines must always be written back exactly as they were read
+    assert_eq!(bms.to_bytes().unwrap(), data);
+
+    for line in bms.lines() {
+        let text = line.to_string();
+
+        assert_eq!(Line::new(&text).to_string(), text);
+    }
+});
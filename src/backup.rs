@@ -0,0 +1,400 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{chart::write_atomic, error::BmsError};
+
+/// Where backups are kept, relative to the song folder. The folder is hidden and the backups
+/// end in `.bak`, so players scanning the song folder don't pick them up as extra charts.
+pub const BACKUP_DIR: &str = ".bmsjoin/backups";
+
+/// How many backups of each file are kept by default.
+pub const DEFAULT_RETENTION: usize = 10;
+
+const BACKUP_EXTENSION: &str = "bak";
+
+/// The length of a `YYYYMMDD-HHMMSS-mmm` timestamp.
+const TIMESTAMP_LEN: usize = 19;
+
+/// A timestamped copy of a chart or audio file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    path: PathBuf,
+    original: PathBuf,
+    timestamp: String,
+}
+
+impl Backup {
+    /// Where the backup is stored.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file the backup was taken from.
+    pub fn original(&self) -> &Path {
+        &self.original
+    }
+
+    /// When the backup was taken, as `YYYYMMDD-HHMMSS-mmm` in UTC.
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+}
+
+/// The backups of the files in one song folder.
+///
+/// A file at `<folder>/sounds/kick.wav` is backed up to
+/// `<folder>/.bmsjoin/backups/sounds/kick.wav.<timestamp>.bak`, keeping the original name and
+/// extension.
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    folder: PathBuf,
+    retention: usize,
+}
+
+impl BackupStore {
+    pub fn new(folder: &Path) -> Self {
+        Self {
+            folder: folder.to_path_buf(),
+            retention: DEFAULT_RETENTION,
+        }
+    }
+
+    /// The store for the folder a chart is in.
+    pub fn for_chart(chart: &Path) -> Self {
+        Self::new(chart.parent().unwrap_or(Path::new("")))
+    }
+
+    /// Sets how many backups of each file are kept. 0 keeps every backup.
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.folder.join(BACKUP_DIR)
+    }
+
+    /// Copies a file into the store, then drops its oldest backups past the retention limit.
+    pub fn backup(&self, file: &Path) -> Result<Backup, BmsError> {
+        let backup = self.next_backup(file)?;
+
+        fs::copy(file, &backup.path).map_err(|e| BmsError::io(file, e))?;
+
+        self.prune(file)?;
+
+        Ok(backup)
+    }
+
+    /// Lists the backups of a file, newest first.
+    pub fn list(&self, file: &Path) -> Result<Vec<Backup>, BmsError> {
+        let (dir, file_name) = self.location(file);
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(BmsError::io(&dir, e)),
+        };
+
+        let mut backups: Vec<Backup> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?;
+
+                let timestamp = name
+                    .strip_suffix(BACKUP_EXTENSION)?
+                    .strip_suffix('.')?
+                    .strip_prefix(file_name.as_str())?
+                    .strip_prefix('.')?
+                    .to_string();
+
+                // Skip the backups of files whose name starts with this one's
+                if timestamp.contains('.') {
+                    return None;
+                }
+
+                Some(Backup {
+                    original: file.to_path_buf(),
+                    path,
+                    timestamp,
+                })
+            })
+            .collect();
+
+        backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Ok(backups)
+    }
+
+    /// Lists every backup in the store, newest first.
+    pub fn list_all(&self) -> Result<Vec<Backup>, BmsError> {
+        let mut backups = Vec::new();
+        let mut dirs = vec![self.dir()];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(BmsError::io(&dir, e)),
+            };
+
+            for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                if let Some(backup) = self.parse_backup(&path) {
+                    backups.push(backup);
+                }
+            }
+        }
+
+        backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Ok(backups)
+    }
+
+    /// Puts a backup back in place of the file it was taken from. The current file is backed
+    /// up first, so a restore can itself be undone, and is only replaced once the backup has
+    /// been written out in full.
+    pub fn restore(&self, backup: &Backup) -> Result<(), BmsError> {
+        // Read first, since backing up the current file may prune the backup being restored
+        let contents = fs::read(&backup.path).map_err(|e| BmsError::io(&backup.path, e))?;
+
+        if backup.original.exists() {
+            self.backup(&backup.original)?;
+        } else if let Some(parent) = backup.original.parent() {
+            fs::create_dir_all(parent).map_err(|e| BmsError::io(parent, e))?;
+        }
+
+        write_atomic(&backup.original, &contents).map_err(|e| BmsError::io(&backup.original, e))
+    }
+
+    /// The folder a file's backups go in, and the file's name.
+    fn location(&self, file: &Path) -> (PathBuf, String) {
+        let file_name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let relative_parent = file
+            .strip_prefix(&self.folder)
+            .ok()
+            .and_then(|relative| relative.parent())
+            .unwrap_or(Path::new(""));
+
+        (self.dir().join(relative_parent), file_name)
+    }
+
+    fn parse_backup(&self, path: &Path) -> Option<Backup> {
+        let relative = path.strip_prefix(self.dir()).ok()?;
+        let name = relative.file_name()?.to_str()?;
+
+        let (file_name, timestamp) = name
+            .strip_suffix(BACKUP_EXTENSION)?
+            .strip_suffix('.')?
+            .rsplit_once('.')?;
+
+        let original = self
+            .folder
+            .join(relative.parent().unwrap_or(Path::new("")))
+            .join(file_name);
+
+        Some(Backup {
+            path: path.to_path_buf(),
+            original,
+            timestamp: timestamp.to_string(),
+        })
+    }
+
+    /// Picks the path for a new backup of `file`, creating its folder. The new backup always
+    /// sorts after the existing ones.
+    fn next_backup(&self, file: &Path) -> Result<Backup, BmsError> {
+        let (dir, file_name) = self.location(file);
+
+        fs::create_dir_all(&dir).map_err(|e| BmsError::io(&dir, e))?;
+
+        let newest = self.list(file)?.into_iter().next();
+        let timestamp = next_timestamp(
+            timestamp(SystemTime::now()),
+            newest.as_ref().map(|backup| backup.timestamp()),
+        );
+
+        Ok(Backup {
+            path: dir.join(format!("{}.{}.{}", file_name, timestamp, BACKUP_EXTENSION)),
+            original: file.to_path_buf(),
+            timestamp,
+        })
+    }
+
+    fn prune(&self, file: &Path) -> Result<(), BmsError> {
+        if self.retention == 0 {
+            return Ok(());
+        }
+
+        for backup in self.list(file)?.iter().skip(self.retention) {
+            fs::remove_file(&backup.path).map_err(|e| BmsError::io(&backup.path, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Picks a timestamp that sorts after `newest`. Backups taken within the same millisecond, or
+/// after the clock went back, get a counter such as `-001` appended to the newest timestamp.
+pub(crate) fn next_timestamp(now: String, newest: Option<&str>) -> String {
+    let Some(newest) = newest.filter(|newest| *newest >= now.as_str()) else {
+        return now;
+    };
+
+    let counter = newest
+        .get(TIMESTAMP_LEN..)
+        .and_then(|counter| counter.strip_prefix('-'))
+        .and_then(|counter| counter.parse::<u32>().ok());
+
+    match counter {
+        Some(counter) => format!("{}-{:03}", &newest[..TIMESTAMP_LEN], counter + 1),
+        None => format!("{}-001", newest),
+    }
+}
+
+/// Formats a time as `YYYYMMDD-HHMMSS-mmm` in UTC, which sorts in chronological order.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let seconds = since_epoch.as_secs();
+    let days = (seconds / 86_400) as i64;
+    let seconds_of_day = seconds % 86_400;
+
+    // Converts days since 1970-01-01 to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::testing::TempFolder;
+
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000-000");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(1_709_210_096_789)),
+            "20240229-123456-789"
+        );
+
+        let now = "20240229-123456-789".to_string();
+
+        assert_eq!(next_timestamp(now.clone(), None), now);
+        assert_eq!(
+            next_timestamp(now.clone(), Some("20240229-123456-788")),
+            now
+        );
+        assert_eq!(
+            next_timestamp(now.clone(), Some("20240229-123456-789")),
+            "20240229-123456-789-001"
+        );
+        assert_eq!(
+            next_timestamp(now.clone(), Some("20240229-123456-789-009")),
+            "20240229-123456-789-010"
+        );
+        assert_eq!(
+            next_timestamp(now, Some("20250101-000000-000")),
+            "20250101-000000-000-001"
+        );
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let folder = TempFolder::new("backup");
+        let chart = folder.join("chart.bme");
+        let store = BackupStore::new(&folder).with_retention(3);
+
+        for version in 0..5 {
+            fs::write(&chart, format!("#TITLE version {}\n", version)).unwrap();
+            store.backup(&chart).unwrap();
+        }
+
+        let backups = store.list(&chart).unwrap();
+
+        // Only the newest three are kept, and they keep the chart's extension
+        assert_eq!(backups.len(), 3);
+        assert!(
+            backups
+                .iter()
+                .all(|backup| backup.path().starts_with(folder.join(BACKUP_DIR)))
+        );
+        assert!(backups[0].path().to_string_lossy().contains("chart.bme."));
+        assert_eq!(
+            fs::read_to_string(backups[0].path()).unwrap(),
+            "#TITLE version 4\n"
+        );
+        assert_eq!(
+            fs::read_to_string(backups[2].path()).unwrap(),
+            "#TITLE version 2\n"
+        );
+
+        fs::write(&chart, "#TITLE broken\n").unwrap();
+        store.restore(&backups[2]).unwrap();
+
+        assert_eq!(fs::read_to_string(&chart).unwrap(), "#TITLE version 2\n");
+
+        // The broken version was backed up before being replaced
+        assert_eq!(
+            fs::read_to_string(store.list(&chart).unwrap()[0].path()).unwrap(),
+            "#TITLE broken\n"
+        );
+    }
+
+    #[test]
+    fn test_deleted_files_can_be_restored() {
+        let folder = TempFolder::new("backup-deleted");
+        let sound = folder.join("sounds").join("kick.wav");
+
+        fs::create_dir_all(sound.parent().unwrap()).unwrap();
+        fs::write(&sound, b"RIFF").unwrap();
+        fs::write(folder.join("kick.wav"), b"other").unwrap();
+
+        let store = BackupStore::new(&folder);
+        store.backup(&folder.join("kick.wav")).unwrap();
+
+        let backup = store.backup(&sound).unwrap();
+        fs::remove_dir_all(sound.parent().unwrap()).unwrap();
+
+        assert_eq!(store.list(&sound).unwrap(), vec![backup.clone()]);
+
+        let all = store.list_all().unwrap();
+        assert_eq!(all.len(), 2);
+        assert!(all.iter().any(|backup| backup.original() == sound));
+
+        store.restore(&backup).unwrap();
+
+        assert_eq!(fs::read(&sound).unwrap(), b"RIFF");
+    }
+}
@@ -14,3 +14,12 @@ pub fn as_str(id: u64) -> String {
         ret
     }
 }
+
+/// Parses a two character keysound ID such as `0A`, ignoring case.
+pub fn as_keysound_id(chars: &str) -> Option<u64> {
+    if chars.len() != 2 || !chars.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    as_id(chars).ok()
+}
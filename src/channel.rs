@@ -0,0 +1,229 @@
+use std::fmt::Display;
+
+use crate::bms::as_str;
+
+/// The channel of a note line (the `cc` in `#mmmcc:`), parsed as a base-36 pair.
+///
+/// Player channels keep the lane as the base-36 value of their second character, so `#00116`
+/// is `P1Visible(6)` and `#001D1` is `P1Mine(1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Channel {
+    /// 01
+    Bgm,
+    /// 02
+    MeasureLength,
+    /// 03
+    Bpm,
+    /// 04
+    BgaBase,
+    /// 06
+    BgaPoor,
+    /// 07
+    BgaLayer,
+    /// 08
+    ExtendedBpm,
+    /// 09
+    Stop,
+    /// 1x
+    P1Visible(u8),
+    /// 2x
+    P2Visible(u8),
+    /// 3x
+    P1Invisible(u8),
+    /// 4x
+    P2Invisible(u8),
+    /// 5x
+    P1LongNote(u8),
+    /// 6x
+    P2LongNote(u8),
+    /// Dx
+    P1Mine(u8),
+    /// Ex
+    P2Mine(u8),
+    Other(u64),
+}
+
+impl Channel {
+    pub fn from_id(id: u64) -> Self {
+        let lane = (id % 36) as u8;
+
+        match (id / 36, lane) {
+            (0, 1) => Channel::Bgm,
+            (0, 2) => Channel::MeasureLength,
+            (0, 3) => Channel::Bpm,
+            (0, 4) => Channel::BgaBase,
+            (0, 6) => Channel::BgaPoor,
+            (0, 7) => Channel::BgaLayer,
+            (0, 8) => Channel::ExtendedBpm,
+            (0, 9) => Channel::Stop,
+            (_, 0) => Channel::Other(id),
+            (1, _) => Channel::P1Visible(lane),
+            (2, _) => Channel::P2Visible(lane),
+            (3, _) => Channel::P1Invisible(lane),
+            (4, _) => Channel::P2Invisible(lane),
+            (5, _) => Channel::P1LongNote(lane),
+            (6, _) => Channel::P2LongNote(lane),
+            (13, _) => Channel::P1Mine(lane),
+            (14, _) => Channel::P2Mine(lane),
+            _ => Channel::Other(id),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match *self {
+            Channel::Bgm => 1,
+            Channel::MeasureLength => 2,
+            Channel::Bpm => 3,
+            Channel::BgaBase => 4,
+            Channel::BgaPoor => 6,
+            Channel::BgaLayer => 7,
+            Channel::ExtendedBpm => 8,
+            Channel::Stop => 9,
+            Channel::P1Visible(lane) => 36 + lane as u64,
+            Channel::P2Visible(lane) => 2 * 36 + lane as u64,
+            Channel::P1Invisible(lane) => 3 * 36 + lane as u64,
+            Channel::P2Invisible(lane) => 4 * 36 + lane as u64,
+            Channel::P1LongNote(lane) => 5 * 36 + lane as u64,
+            Channel::P2LongNote(lane) => 6 * 36 + lane as u64,
+            Channel::P1Mine(lane) => 13 * 36 + lane as u64,
+            Channel::P2Mine(lane) => 14 * 36 + lane as u64,
+            Channel::Other(id) => id,
+        }
+    }
+
+    /// Whether the values on this channel are `#WAV` references.
+    pub fn references_keysound(&self) -> bool {
+        matches!(
+            self,
+            Channel::Bgm
+                | Channel::P1Visible(_)
+                | Channel::P2Visible(_)
+                | Channel::P1Invisible(_)
+                | Channel::P2Invisible(_)
+                | Channel::P1LongNote(_)
+                | Channel::P2LongNote(_)
+        )
+    }
+
+    /// The playable lane of the channel, if it belongs to a player.
+    pub fn lane(&self) -> Option<u8> {
+        match *self {
+            Channel::P1Visible(lane)
+            | Channel::P2Visible(lane)
+            | Channel::P1Invisible(lane)
+            | Channel::P2Invisible(lane)
+            | Channel::P1LongNote(lane)
+            | Channel::P2LongNote(lane)
+            | Channel::P1Mine(lane)
+            | Channel::P2Mine(lane) => Some(lane),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", as_str(self.id()))
+    }
+}
+
+/// Which channels a keysound replacement is allowed to rewrite.
+///
+/// Only channels whose values are `#WAV` references can be enabled. BPM, BGA, STOP and mine
+/// channels are never touched, since their values mean something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplacePolicy {
+    /// 01
+    pub bgm: bool,
+    /// 1x and 2x
+    pub visible: bool,
+    /// 3x and 4x
+    pub invisible: bool,
+    /// 5x and 6x
+    pub long_notes: bool,
+    /// Whether `#LNOBJ` follows the replaced keysound, so that long note endings stay endings.
+    pub ln_obj: bool,
+}
+
+impl ReplacePolicy {
+    pub fn allows(&self, channel: Channel) -> bool {
+        match channel {
+            Channel::Bgm => self.bgm,
+            Channel::P1Visible(_) | Channel::P2Visible(_) => self.visible,
+            Channel::P1Invisible(_) | Channel::P2Invisible(_) => self.invisible,
+            Channel::P1LongNote(_) | Channel::P2LongNote(_) => self.long_notes,
+            _ => false,
+        }
+    }
+}
+
+impl Default for ReplacePolicy {
+    fn default() -> Self {
+        Self {
+            bgm: true,
+            visible: true,
+            invisible: true,
+            long_notes: true,
+            ln_obj: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bms::as_id;
+
+    use super::*;
+
+    #[test]
+    fn test_channel_round_trip() {
+        for id in 0..36 * 36 {
+            let channel = Channel::from_id(id);
+
+            assert_eq!(channel.id(), id);
+            assert_eq!(as_id(channel.to_string()).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn test_parse_channels() {
+        let channel = |s: &str| Channel::from_id(as_id(s).unwrap());
+
+        assert_eq!(channel("01"), Channel::Bgm);
+        assert_eq!(channel("02"), Channel::MeasureLength);
+        assert_eq!(channel("16"), Channel::P1Visible(6));
+        assert_eq!(channel("29"), Channel::P2Visible(9));
+        assert_eq!(channel("3A"), Channel::P1Invisible(10));
+        assert_eq!(channel("51"), Channel::P1LongNote(1));
+        assert_eq!(channel("D1"), Channel::P1Mine(1));
+        assert_eq!(channel("e9"), Channel::P2Mine(9));
+        assert_eq!(channel("05"), Channel::Other(5));
+        assert_eq!(channel("10"), Channel::Other(36));
+        assert_eq!(channel("A1"), Channel::Other(as_id("A1").unwrap()));
+
+        assert_eq!(Channel::P1Mine(1).to_string(), "D1");
+        assert_eq!(Channel::P1Visible(6).lane(), Some(6));
+        assert_eq!(Channel::Bgm.lane(), None);
+    }
+
+    #[test]
+    fn test_replace_policy() {
+        let policy = ReplacePolicy::default();
+
+        for channel in ["01", "11", "29", "31", "4Z", "51", "69"] {
+            assert!(policy.allows(Channel::from_id(as_id(channel).unwrap())));
+        }
+
+        for channel in ["02", "03", "04", "06", "07", "08", "09", "D1", "E1", "71"] {
+            assert!(!policy.allows(Channel::from_id(as_id(channel).unwrap())));
+        }
+
+        let policy = ReplacePolicy {
+            bgm: false,
+            ..Default::default()
+        };
+
+        assert!(!policy.allows(Channel::Bgm));
+        assert!(policy.allows(Channel::P1Visible(1)));
+    }
+}
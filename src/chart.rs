@@ -0,0 +1,1726 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use similar::TextDiff;
+
+use crate::{
+    bms::{as_id, as_str},
+    channel::{Channel, ReplacePolicy},
+    control::{BranchTree, Condition, Outcome, parse_branches},
+    encoding::{TextEncoding, is_raw_byte},
+    error::{BmsError, Diagnostic, ParseError},
+    header::{Header, HeaderKind},
+    line::{Keysound, Line, LineEnding, Note, parse_lines},
+    measure::{MeasureLength, MeasureLengths},
+    merge::merge_lines,
+    resource::{FileResolver, FileStatus, Resource, ResourceKind, classify_files},
+};
+
+/// A keysound ID that notes refer to without a matching `#WAV` definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndefinedKeysound {
+    pub keysound_id: u64,
+    /// The measure, channel and beat of every object using the ID. Beats follow the chart's
+    /// measure lengths, as in [`MeasureLengths::beat`].
+    pub locations: Vec<(u32, Channel, f64)>,
+}
+
+impl Display for UndefinedKeysound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let locations = self
+            .locations
+            .iter()
+            .map(|(measure, channel, beat)| format!("#{:03}{} (beat {})", measure, channel, beat))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(
+            f,
+            "{} ({} objects): {}",
+            as_str(self.keysound_id),
+            self.locations.len(),
+            locations
+        )
+    }
+}
+
+/// A keysound whose file can't be found, even the way players look for it.
+#[derive(Debug, Clone)]
+pub struct MissingKeysound {
+    pub keysound: Keysound,
+    /// How many objects use the keysound.
+    pub uses: usize,
+}
+
+impl Display for MissingKeysound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ({} objects)",
+            as_str(self.keysound.keysound_id()),
+            self.keysound.keysound_file(),
+            self.uses
+        )
+    }
+}
+
+/// A keysound definition and the branches of the notes that play it.
+#[derive(Debug, Clone)]
+pub struct KeysoundUsage {
+    pub keysound: Keysound,
+    /// The position of the definition within [`BMSFile::lines`].
+    pub line: usize,
+    /// The conditions of every distinct branch with notes using the definition. An empty list
+    /// of conditions is the part of the chart outside of any branch.
+    pub branches: Vec<Vec<Condition>>,
+}
+
+impl KeysoundUsage {
+    /// Whether no note in any branch plays the definition.
+    pub fn is_unused(&self) -> bool {
+        self.branches.is_empty()
+    }
+}
+
+/// A chart loaded from disk, keeping every line in its original position so that saving only
+/// changes the lines that were edited.
+#[derive(Debug, Clone)]
+pub struct BMSFile {
+    path: PathBuf,
+    encoding: TextEncoding,
+
+    /// The ending used for lines that didn't have one in the original file.
+    line_ending: LineEnding,
+    trailing_newline: bool,
+
+    /// Every line of the chart, in the order they appear in the file.
+    lines: Vec<Line>,
+    /// The line of the first definition of each keysound ID, rebuilt whenever lines are added
+    /// or removed.
+    keysound_index: HashMap<u64, usize>,
+    diagnostics: Vec<Diagnostic>,
+
+    /// The bytes and modification time of the file when it was last read or saved, used to
+    /// notice edits made by other programs.
+    loaded: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+impl BMSFile {
+    pub fn from_path(path: &Path) -> Result<Self, BmsError> {
+        let modified = modified_time(path);
+        let bytes = fs::read(path).map_err(|e| BmsError::io(path, e))?;
+
+        Ok(Self {
+            modified,
+            ..Self::from_bytes(path, &bytes)
+        })
+    }
+
+    /// Parses a chart from its raw bytes. `path` is only used for diagnostics and saving.
+    pub fn from_bytes(path: &Path, bytes: &[u8]) -> Self {
+        let (text, encoding) = TextEncoding::decode(bytes);
+
+        Self {
+            loaded: bytes.to_vec(),
+            ..Self::from_text(path, &text, encoding)
+        }
+    }
+
+    fn from_text(path: &Path, text: &str, encoding: TextEncoding) -> Self {
+        let (lines, mut diagnostics) = parse_lines(text, Some(path));
+
+        // Lines that couldn't be decoded are still saved unchanged, but their text is unreliable
+        for (i, (line, _)) in LineEnding::split(text).into_iter().enumerate() {
+            if let Some(column) = line.chars().position(is_raw_byte) {
+                diagnostics.push(Diagnostic::new(
+                    Some(path),
+                    i + 1,
+                    ParseError::new(
+                        column + 1,
+                        format!("The line isn't valid {}", encoding.name()),
+                    ),
+                ));
+            }
+        }
+
+        diagnostics.extend(parse_branches(&lines, Some(path)).1);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+
+        let line_ending = lines
+            .first()
+            .map(|line| line.ending())
+            .filter(|ending| *ending != LineEnding::None)
+            .unwrap_or_default();
+
+        let trailing_newline = lines
+            .last()
+            .is_none_or(|line| line.ending() != LineEnding::None);
+
+        BMSFile {
+            path: path.to_path_buf(),
+            encoding,
+            line_ending,
+            trailing_newline,
+            keysound_index: index_keysounds(&lines),
+            lines,
+            diagnostics,
+            loaded: Vec::new(),
+            modified: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
+    /// Problems found in the chart when it was last read.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// The `#RANDOM` and `#SWITCH` blocks of the chart, and which of their branches each line
+    /// is in.
+    pub fn branches(&self) -> BranchTree {
+        parse_branches(&self.lines, Some(&self.path)).0
+    }
+
+    /// Serialises the chart in its original encoding.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BmsError> {
+        self.encoding
+            .encode(&self.to_text())
+            .map_err(|e| BmsError::io(&self.path, e))
+    }
+
+    /// The text of the chart as it would be saved, before encoding.
+    pub fn to_text(&self) -> String {
+        let last = self.lines.len().saturating_sub(1);
+        let mut text = String::new();
+
+        for (i, line) in self.lines.iter().enumerate() {
+            let ending = if i == last && !self.trailing_newline {
+                LineEnding::None
+            } else if line.ending() == LineEnding::None {
+                self.line_ending
+            } else {
+                line.ending()
+            };
+
+            text.push_str(&line.to_string());
+            text.push_str(ending.as_str());
+        }
+
+        text
+    }
+
+    /// Replaces the whole chart with `text`, as if it had been edited by hand. The path and
+    /// encoding stay the same, and saving still checks against what was last read from disk.
+    pub fn set_text(&mut self, text: &str) {
+        let parsed = Self::from_text(&self.path, text, self.encoding);
+
+        self.line_ending = parsed.line_ending;
+        self.trailing_newline = parsed.trailing_newline;
+        self.lines = parsed.lines;
+        self.keysound_index = parsed.keysound_index;
+        self.diagnostics = parsed.diagnostics;
+    }
+
+    /// Shows how `modified` differs from this chart as a unified diff. Returns an empty string
+    /// if saving `modified` wouldn't change anything.
+    pub fn diff(&self, modified: &BMSFile) -> String {
+        let old = self.to_text();
+        let new = modified.to_text();
+
+        if old == new {
+            return String::new();
+        }
+
+        TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .header(
+                &self.path.display().to_string(),
+                &modified.path.display().to_string(),
+            )
+            .to_string()
+    }
+
+    pub fn notes(&self) -> impl Iterator<Item = &Note> {
+        self.lines.iter().filter_map(|line| line.as_note())
+    }
+
+    pub fn notes_mut(&mut self) -> impl Iterator<Item = &mut Note> {
+        self.lines.iter_mut().filter_map(|line| match line {
+            Line::Note(note) => Some(note),
+            _ => None,
+        })
+    }
+
+    pub fn keysounds(&self) -> impl Iterator<Item = &Keysound> {
+        self.lines.iter().filter_map(|line| line.as_keysound())
+    }
+
+    /// Finds the position of a keysound's definition within [`BMSFile::lines`].
+    pub fn find_keysound(&self, id: u64) -> Option<usize> {
+        self.keysound_index.get(&id).copied()
+    }
+
+    /// Rebuilds the keysound index after lines were added or removed.
+    fn reindex(&mut self) {
+        self.keysound_index = index_keysounds(&self.lines);
+    }
+
+    /// Removes every keysound definition for which `f` returns false, leaving all other lines
+    /// where they are.
+    pub fn retain_keysounds<F: FnMut(&Keysound) -> bool>(&mut self, mut f: F) {
+        self.lines.retain(|line| match line {
+            Line::Keysound(keysound) => f(keysound),
+            _ => true,
+        });
+
+        self.reindex();
+    }
+
+    /// Whether the chart defines the given keysound.
+    pub fn has_keysound(&self, keysound_id: u64) -> bool {
+        self.get_keysound(keysound_id).is_some()
+    }
+
+    /// Whether the keysound is defined and used by at least one note.
+    pub fn uses_keysound(&self, keysound_id: u64) -> bool {
+        // Make sure the keysound actually exists
+        if self.get_keysound(keysound_id).is_none() {
+            return false;
+        }
+
+        self.keysound_notes()
+            .any(|note| note.uses_keysound(keysound_id))
+    }
+
+    /// The notes whose values are keysound references, skipping BPM, BGA, STOP and mine
+    /// channels.
+    pub fn keysound_notes(&self) -> impl Iterator<Item = &Note> {
+        self.notes()
+            .filter(|note| note.channel().references_keysound())
+    }
+
+    pub fn get_keysound(&self, id: u64) -> Option<&Keysound> {
+        self.find_keysound(id)
+            .and_then(|index| self.lines[index].as_keysound())
+    }
+
+    pub fn get_keysound_mut(&mut self, id: u64) -> Option<&mut Keysound> {
+        match self.find_keysound(id).map(|index| &mut self.lines[index]) {
+            Some(Line::Keysound(keysound)) => Some(keysound),
+            _ => None,
+        }
+    }
+
+    /// Finds which branches use each keysound definition, in the order they are defined.
+    ///
+    /// A note only plays a definition that can be played along with it, and when an ID is
+    /// defined more than once the last definition played wins. A definition counts as used
+    /// unless a later one is certain to replace it for every note using it.
+    pub fn keysound_usage(&self) -> Vec<KeysoundUsage> {
+        let branches = self.branches();
+
+        let mut users: HashMap<u64, Vec<usize>> = HashMap::new();
+
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Line::Note(note) = line
+                && note.channel().references_keysound()
+                && branches.is_reachable(i)
+            {
+                // 00 marks an empty position rather than a keysound
+                for id in note.keysounds_used().into_iter().filter(|id| *id != 0) {
+                    users.entry(id).or_default().push(i);
+                }
+            }
+        }
+
+        let definitions: Vec<(usize, &Keysound)> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| line.as_keysound().map(|keysound| (i, keysound)))
+            .collect();
+
+        definitions
+            .iter()
+            .map(|(line, keysound)| {
+                let id = keysound.keysound_id();
+
+                let later: Vec<usize> = definitions
+                    .iter()
+                    .filter(|(other, ks)| other > line && ks.keysound_id() == id)
+                    .map(|(other, _)| *other)
+                    .collect();
+
+                let mut used_in: Vec<Vec<Condition>> = Vec::new();
+
+                for note in users.get(&id).into_iter().flatten() {
+                    if !branches.can_play_together(*line, *note)
+                        || later
+                            .iter()
+                            .any(|other| branches.always_played_with(*other, &[*line, *note]))
+                    {
+                        continue;
+                    }
+
+                    let conditions = branches.conditions(*note).to_vec();
+
+                    if !used_in.contains(&conditions) {
+                        used_in.push(conditions);
+                    }
+                }
+
+                KeysoundUsage {
+                    keysound: (*keysound).clone(),
+                    line: *line,
+                    branches: used_in,
+                }
+            })
+            .collect()
+    }
+
+    /// The keysound definitions that no note plays in any branch. See
+    /// [`keysound_usage`](Self::keysound_usage).
+    pub fn get_unused_keysounds(&self) -> Vec<Keysound> {
+        self.keysound_usage()
+            .into_iter()
+            .filter(KeysoundUsage::is_unused)
+            .map(|usage| usage.keysound)
+            .collect()
+    }
+
+    /// Removes the definitions that no note plays in any branch, leaving any other definition
+    /// of the same IDs alone. Returns the removed keysounds.
+    pub fn remove_unused_keysounds(&mut self) -> Vec<Keysound> {
+        let unused: HashSet<usize> = self
+            .keysound_usage()
+            .into_iter()
+            .filter(KeysoundUsage::is_unused)
+            .map(|usage| usage.line)
+            .collect();
+
+        let mut removed = Vec::new();
+        let mut index = 0;
+
+        self.lines.retain(|line| {
+            let keep = !unused.contains(&index);
+            index += 1;
+
+            if !keep && let Line::Keysound(keysound) = line {
+                removed.push(keysound.clone());
+            }
+
+            keep
+        });
+
+        self.reindex();
+
+        removed
+    }
+
+    /// A flat copy of the chart for one outcome of its `#RANDOM` and `#SWITCH` blocks, to be
+    /// saved at `path`. Only the lines played for the outcome are kept, and the control flow
+    /// commands are left out.
+    pub fn expand(&self, outcome: &Outcome, path: &Path) -> BMSFile {
+        let lines: Vec<Line> = self
+            .branches()
+            .played_lines(outcome)
+            .into_iter()
+            .filter_map(|i| match &self.lines[i] {
+                Line::Control(_) => None,
+                line => Some(line.clone()),
+            })
+            .collect();
+
+        BMSFile {
+            path: path.to_path_buf(),
+            encoding: self.encoding,
+            line_ending: self.line_ending,
+            trailing_newline: self.trailing_newline,
+            keysound_index: index_keysounds(&lines),
+            lines,
+            diagnostics: Vec::new(),
+            loaded: Vec::new(),
+            modified: None,
+        }
+    }
+
+    /// Describes a branch by the values of its blocks, such as `#RANDOM 2 = 1`.
+    pub fn describe_branch(&self, conditions: &[Condition]) -> String {
+        if conditions.is_empty() {
+            return "outside of any branch".to_string();
+        }
+
+        conditions
+            .iter()
+            .map(|condition| {
+                let block = condition
+                    .block
+                    .and_then(|line| self.lines.get(line))
+                    .map(|line| line.to_string().trim().to_string())
+                    .unwrap_or_else(|| "no #RANDOM".to_string());
+
+                format!("{} = {}", block, condition.values)
+            })
+            .collect::<Vec<String>>()
+            .join(" > ")
+    }
+
+    /// Picks the most used of the given keysounds, preferring the lowest ID on ties.
+    pub fn most_used_keysound(&self, ids: &[u64]) -> Option<u64> {
+        ids.iter()
+            .rev()
+            .max_by_key(|id| self.count_keysound_uses(**id))
+            .copied()
+    }
+
+    /// Counts how many objects in the chart reference the given keysound.
+    pub fn count_keysound_uses(&self, keysound_id: u64) -> usize {
+        self.keysound_notes()
+            .map(|note| {
+                note.keysounds()
+                    .iter()
+                    .filter(|id| **id == keysound_id)
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Finds every keysound ID used by a note that has no `#WAV` definition.
+    pub fn get_undefined_keysounds(&self) -> Vec<UndefinedKeysound> {
+        let lengths = self.measure_lengths();
+        let mut undefined: BTreeMap<u64, Vec<(u32, Channel, f64)>> = BTreeMap::new();
+
+        for note in self.keysound_notes() {
+            let count = note.keysounds().len() as f64;
+
+            for (i, id) in note.keysounds().iter().enumerate() {
+                // 00 marks an empty position rather than a keysound
+                if *id != 0 && !self.has_keysound(*id) {
+                    let beat = lengths.beat(note.measure(), i as f64 / count);
+
+                    undefined
+                        .entry(*id)
+                        .or_default()
+                        .push((note.measure(), note.channel(), beat));
+                }
+            }
+        }
+
+        undefined
+            .into_iter()
+            .map(|(keysound_id, locations)| UndefinedKeysound {
+                keysound_id,
+                locations,
+            })
+            .collect()
+    }
+
+    /// Finds every keysound whose file doesn't exist in the chart's folder. Files are looked up
+    /// with a [`FileResolver`], so a keysound that players would find isn't missing.
+    pub fn get_missing_keysounds(&self) -> Vec<MissingKeysound> {
+        let resolver = FileResolver::new(self.folder());
+
+        self.keysounds()
+            .filter(|keysound| resolver.find(keysound.keysound_file()).is_none())
+            .map(|keysound| MissingKeysound {
+                keysound: keysound.clone(),
+                uses: self.count_keysound_uses(keysound.keysound_id()),
+            })
+            .collect()
+    }
+
+    /// Removes a keysound's definition and empties every object using it, returning how many
+    /// objects were removed. `#LNOBJ` is left alone.
+    pub fn remove_keysound(&mut self, id: u64) -> usize {
+        let policy = ReplacePolicy {
+            ln_obj: false,
+            ..ReplacePolicy::default()
+        };
+
+        let removed = self.replace_keysound(id, 0, &policy);
+        self.retain_keysounds(|keysound| keysound.keysound_id() != id);
+
+        removed
+    }
+
+    /// Rewrites every object using `old_id` to use `new_id` on the channels the policy allows,
+    /// returning how many objects were changed.
+    ///
+    /// On the visible channels, `#LNOBJ` turns objects into long note endings. If `old_id` is
+    /// the `#LNOBJ`, it only moves to `new_id` when the policy allows it and `new_id` has no
+    /// visible objects of its own. Otherwise, and whenever `new_id` is the `#LNOBJ`, visible
+    /// objects are left alone so that no note changes between a normal note and an ending.
+    pub fn replace_keysound(&mut self, old_id: u64, new_id: u64, policy: &ReplacePolicy) -> usize {
+        let ln_obj = self.ln_obj().filter(|_| new_id != 0);
+
+        let move_ln_obj = ln_obj == Some(old_id)
+            && policy.ln_obj
+            && !self.notes().any(|note| {
+                matches!(
+                    note.channel(),
+                    Channel::P1Visible(_) | Channel::P2Visible(_)
+                ) && note.uses_keysound(new_id)
+            });
+
+        let policy = if ln_obj.is_some_and(|id| id == old_id || id == new_id) && !move_ln_obj {
+            &ReplacePolicy {
+                visible: false,
+                ..*policy
+            }
+        } else {
+            policy
+        };
+
+        let replaced = self
+            .notes_mut()
+            .filter_map(|note| note.replace_keysounds(old_id, new_id, policy))
+            .sum();
+
+        if move_ln_obj {
+            self.set_ln_obj(new_id);
+        }
+
+        replaced
+    }
+
+    /// Rewrites every note using one of `ids` to use `target` instead, and removes the
+    /// definitions of the merged keysounds. Definitions that are still referenced on channels
+    /// the policy skipped are kept. Returns the merged IDs along with how many objects were
+    /// rewritten for each of them.
+    pub fn merge_keysounds(
+        &mut self,
+        target: u64,
+        ids: &[u64],
+        policy: &ReplacePolicy,
+    ) -> Vec<(u64, usize)> {
+        let mut summary = Vec::new();
+
+        for old_id in ids.iter().filter(|id| **id != target) {
+            let replaced = self.replace_keysound(*old_id, target, policy);
+
+            if !self.uses_keysound(*old_id) {
+                self.retain_keysounds(|ks| ks.keysound_id() != *old_id);
+            }
+
+            summary.push((*old_id, replaced));
+        }
+
+        summary
+    }
+
+    /// Swaps the files of two keysounds, so that every note using one plays the other. Only the
+    /// two definitions change. Returns false if either keysound isn't defined.
+    pub fn swap_keysounds(&mut self, a: u64, b: u64) -> bool {
+        let (Some(file_a), Some(file_b)) = (
+            self.get_keysound(a)
+                .map(|ks| ks.keysound_file().to_string()),
+            self.get_keysound(b)
+                .map(|ks| ks.keysound_file().to_string()),
+        ) else {
+            return false;
+        };
+
+        if let Some(keysound) = self.get_keysound_mut(a) {
+            keysound.set_keysound_file(file_b);
+        }
+
+        if let Some(keysound) = self.get_keysound_mut(b) {
+            keysound.set_keysound_file(file_a);
+        }
+
+        true
+    }
+
+    /// Points every definition using the file `from` at `to` instead, returning how many were
+    /// changed. Definitions are matched with a [`FileResolver`], so one that names the same
+    /// file with another case or extension is changed too. The file on disk is left alone.
+    pub fn rename_keysound_file(&mut self, from: &str, to: &str) -> usize {
+        let resolver = FileResolver::new(self.folder());
+        let from_path = resolver.resolve(from);
+        let mut renamed = 0;
+
+        self.lines.iter_mut().for_each(|line| {
+            if let Line::Keysound(keysound) = line
+                && (keysound.keysound_file() == from
+                    || resolver.resolve(keysound.keysound_file()) == from_path)
+            {
+                keysound.set_keysound_file(to.to_string());
+                renamed += 1;
+            }
+        });
+
+        renamed
+    }
+
+    /// Gets the first header line of the given kind.
+    pub fn get_header(&self, kind: HeaderKind) -> Option<&Header> {
+        self.lines
+            .iter()
+            .filter_map(|line| line.as_header())
+            .find(|header| header.kind() == kind)
+    }
+
+    /// Gets the raw value of a header, or None if the chart doesn't define it.
+    pub fn header(&self, kind: HeaderKind) -> Option<&str> {
+        self.get_header(kind).map(|header| header.value())
+    }
+
+    /// Sets a header in place, or adds it after the last header line outside of any `#RANDOM`
+    /// or `#SWITCH` if the chart doesn't have it yet.
+    pub fn set_header<T: ToString>(&mut self, kind: HeaderKind, value: T) {
+        let existing = self.lines.iter_mut().find_map(|line| match line {
+            Line::Header(header) if header.kind() == kind => Some(header),
+            _ => None,
+        });
+
+        if let Some(header) = existing {
+            header.set_value(value.to_string());
+            return;
+        }
+
+        let branches = self.branches();
+
+        let index = match self
+            .lines
+            .iter()
+            .enumerate()
+            .rposition(|(i, line)| line.as_header().is_some() && !branches.is_conditional(i))
+        {
+            Some(last_header) => last_header + 1,
+            None => self
+                .lines
+                .iter()
+                .position(|line| !matches!(line, Line::Generic(_)))
+                .unwrap_or(self.lines.len()),
+        };
+
+        let mut header = Header::new(kind, value.to_string());
+        header.ending = self.line_ending;
+
+        self.lines.insert(index, Line::Header(header));
+        self.reindex();
+    }
+
+    /// Removes every header line of the given kind.
+    pub fn remove_header(&mut self, kind: HeaderKind) {
+        self.lines
+            .retain(|line| line.as_header().is_none_or(|header| header.kind() != kind));
+        self.reindex();
+    }
+
+    /// The length of every measure, from its `#xxx02` lines. Lines in every branch are counted,
+    /// with later ones winning, so a chart with `#RANDOM` blocks should be expanded first to get
+    /// the lengths of one outcome.
+    pub fn measure_lengths(&self) -> MeasureLengths {
+        self.lines
+            .iter()
+            .filter_map(Line::as_measure_length)
+            .map(|length| (length.measure(), length.length()))
+            .collect()
+    }
+
+    /// Sets the length of a measure on every line that changes it. If there are none, a line is
+    /// added outside of any branch, before the first object of the measure or of a later one.
+    pub fn set_measure_length(&mut self, measure: u32, length: f64) -> Result<(), BmsError> {
+        let mut new_length = MeasureLength::new(measure, length)?;
+        let mut found = false;
+
+        for line in self.lines.iter_mut() {
+            if let Line::MeasureLength(existing) = line
+                && existing.measure() == measure
+            {
+                existing.set_length(length)?;
+                found = true;
+            }
+        }
+
+        if found {
+            return Ok(());
+        }
+
+        let branches = self.branches();
+
+        let index = self
+            .lines
+            .iter()
+            .enumerate()
+            .position(|(i, line)| {
+                !branches.is_conditional(i)
+                    && match line {
+                        Line::Note(note) => note.measure() >= measure,
+                        Line::MeasureLength(other) => other.measure() > measure,
+                        _ => false,
+                    }
+            })
+            .unwrap_or(self.lines.len());
+
+        new_length.ending = self.line_ending;
+        self.lines.insert(index, Line::MeasureLength(new_length));
+        self.reindex();
+
+        Ok(())
+    }
+
+    /// Removes every line that changes the length of a measure, making it 4/4 again. Returns
+    /// how many lines were removed.
+    pub fn remove_measure_length(&mut self, measure: u32) -> usize {
+        let before = self.lines.len();
+
+        self.lines.retain(|line| {
+            line.as_measure_length()
+                .is_none_or(|length| length.measure() != measure)
+        });
+        self.reindex();
+
+        before - self.lines.len()
+    }
+
+    /// Reads the chart from disk again, picking up changes made in other editors. If the file
+    /// can't be read, the chart is left empty.
+    pub fn reload(&mut self) -> Result<(), BmsError> {
+        match Self::from_path(&self.path) {
+            Ok(new_bms) => {
+                self.encoding = new_bms.encoding;
+                self.line_ending = new_bms.line_ending;
+                self.trailing_newline = new_bms.trailing_newline;
+                self.lines = new_bms.lines;
+                self.keysound_index = new_bms.keysound_index;
+                self.diagnostics = new_bms.diagnostics;
+                self.loaded = new_bms.loaded;
+                self.modified = new_bms.modified;
+
+                Ok(())
+            }
+            Err(e) => {
+                self.lines.clear();
+                self.keysound_index.clear();
+                self.diagnostics.clear();
+
+                Err(e)
+            }
+        }
+    }
+
+    /// The chart as it was when it was last read or saved, without any unsaved edits.
+    pub fn as_loaded(&self) -> BMSFile {
+        Self {
+            modified: self.modified,
+            ..Self::from_bytes(&self.path, &self.loaded)
+        }
+    }
+
+    /// Saves the chart, refusing with [`BmsError::Conflict`] if another program changed the
+    /// file since it was loaded. See [`BMSFile::merge_external_changes`] and
+    /// [`BMSFile::overwrite`] for ways forward.
+    pub fn save(&mut self) -> Result<(), BmsError> {
+        if self.changed_on_disk()? {
+            return Err(BmsError::Conflict {
+                path: self.path.clone(),
+            });
+        }
+
+        self.overwrite()
+    }
+
+    /// Saves the chart even if another program changed the file since it was loaded.
+    ///
+    /// The chart is written to a temporary file next to it, which then replaces the chart, so
+    /// a crash part way through never leaves a truncated chart behind.
+    pub fn overwrite(&mut self) -> Result<(), BmsError> {
+        let bytes = self.to_bytes()?;
+
+        write_atomic(&self.path, &bytes).map_err(|e| BmsError::io(&self.path, e))?;
+
+        self.loaded = bytes;
+        self.modified = modified_time(&self.path);
+
+        Ok(())
+    }
+
+    /// Whether the file on disk differs from what was loaded or last saved. A missing file
+    /// doesn't count as a change.
+    pub fn changed_on_disk(&self) -> Result<bool, BmsError> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(BmsError::io(&self.path, e)),
+        };
+
+        // An unchanged modification time and size is trusted without reading the file
+        if self.modified.is_some()
+            && metadata.modified().ok() == self.modified
+            && metadata.len() == self.loaded.len() as u64
+        {
+            return Ok(false);
+        }
+
+        let bytes = fs::read(&self.path).map_err(|e| BmsError::io(&self.path, e))?;
+
+        Ok(bytes != self.loaded)
+    }
+
+    /// Folds the changes another program made to the file on disk into this chart, keeping
+    /// the unsaved edits. Fails with [`BmsError::MergeConflict`] if both touched the same
+    /// lines, in which case the chart is left as it was.
+    pub fn merge_external_changes(&mut self) -> Result<(), BmsError> {
+        let modified = modified_time(&self.path);
+        let bytes = fs::read(&self.path).map_err(|e| BmsError::io(&self.path, e))?;
+
+        let (theirs, encoding) = TextEncoding::decode(&bytes);
+
+        if encoding != self.encoding {
+            return Err(BmsError::io(
+                &self.path,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "The file was saved as {} instead of {}.",
+                        encoding.name(),
+                        self.encoding.name()
+                    ),
+                ),
+            ));
+        }
+
+        let base = self
+            .encoding
+            .decode_exact(&self.loaded)
+            .unwrap_or_else(|| TextEncoding::decode(&self.loaded).0);
+
+        let merged = merge_lines(&base, &self.to_text(), &theirs).map_err(|conflicts| {
+            BmsError::MergeConflict {
+                path: self.path.clone(),
+                conflicts,
+            }
+        })?;
+
+        *self = Self {
+            loaded: bytes,
+            modified,
+            ..Self::from_text(&self.path, &merged, encoding)
+        };
+
+        Ok(())
+    }
+
+    /// Resolves a keysound's file relative to the chart's folder, the way players do. See
+    /// [`FileResolver`].
+    pub fn keysound_path(&self, keysound: &Keysound) -> PathBuf {
+        FileResolver::new(self.folder()).resolve(keysound.keysound_file())
+    }
+
+    /// The folder containing the chart and its resources. A chart given by its bare file name is
+    /// in the current folder.
+    pub fn folder(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Finds the files of the given (removed) keysounds that no remaining keysound refers to.
+    pub fn get_orphaned_files(&self, removed: &[Keysound]) -> Vec<PathBuf> {
+        let resolver = FileResolver::new(self.folder());
+
+        let used: HashSet<PathBuf> = self
+            .keysounds()
+            .map(|keysound| resolver.resolve(keysound.keysound_file()))
+            .collect();
+
+        let mut orphaned_files: Vec<PathBuf> = removed
+            .iter()
+            .map(|removed| resolver.resolve(removed.keysound_file()))
+            .filter(|file| !used.contains(file))
+            .collect();
+
+        orphaned_files.sort();
+        orphaned_files.dedup();
+        orphaned_files
+    }
+
+    /// Lists the files that the chart refers to: keysounds, `#BMP` images and videos, and the
+    /// `#STAGEFILE`, `#BANNER`, `#BACKBMP` and `#PREVIEW` headers.
+    pub fn resources(&self) -> Vec<Resource> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Keysound(keysound) => Some(Resource {
+                    command: format!("WAV{}", as_str(keysound.keysound_id())),
+                    file: keysound.keysound_file().to_string(),
+                }),
+                Line::Header(header)
+                    if matches!(
+                        header.kind(),
+                        HeaderKind::StageFile
+                            | HeaderKind::Banner
+                            | HeaderKind::BackBmp
+                            | HeaderKind::Preview
+                    ) && !header.value().is_empty() =>
+                {
+                    Some(Resource {
+                        command: header.kind().command().to_string(),
+                        file: header.value().to_string(),
+                    })
+                }
+                Line::Generic(generic) => Resource::from_bmp_line(generic.line()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Finds the audio files in the chart's folder that nothing in the chart refers to.
+    pub fn get_unused_files(&self) -> Result<Vec<PathBuf>, BmsError> {
+        Ok(classify_files(self.folder(), &[self])?
+            .into_iter()
+            .filter(|usage| {
+                usage.status == FileStatus::Unreferenced && usage.kind == Some(ResourceKind::Audio)
+            })
+            .map(|usage| usage.path)
+            .collect())
+    }
+}
+
+/// Maps each keysound ID to the line of its first definition.
+fn index_keysounds(lines: &[Line]) -> HashMap<u64, usize> {
+    let mut index = HashMap::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(keysound) = line.as_keysound() {
+            index.entry(keysound.keysound_id()).or_insert(i);
+        }
+    }
+
+    index
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Writes `bytes` to a temporary file next to `path`, then renames it over `path`.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let result = (|| {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temp_path, metadata.permissions())?;
+        }
+
+        fs::rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// Typed accessors for the standard header commands.
+impl BMSFile {
+    pub fn player(&self) -> Option<u32> {
+        self.get_header(HeaderKind::Player)?.parse()
+    }
+
+    pub fn set_player(&mut self, player: u32) {
+        self.set_header(HeaderKind::Player, player);
+    }
+
+    pub fn genre(&self) -> Option<&str> {
+        self.header(HeaderKind::Genre)
+    }
+
+    pub fn set_genre(&mut self, genre: &str) {
+        self.set_header(HeaderKind::Genre, genre);
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.header(HeaderKind::Title)
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.set_header(HeaderKind::Title, title);
+    }
+
+    pub fn subtitle(&self) -> Option<&str> {
+        self.header(HeaderKind::Subtitle)
+    }
+
+    pub fn set_subtitle(&mut self, subtitle: &str) {
+        self.set_header(HeaderKind::Subtitle, subtitle);
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.header(HeaderKind::Artist)
+    }
+
+    pub fn set_artist(&mut self, artist: &str) {
+        self.set_header(HeaderKind::Artist, artist);
+    }
+
+    pub fn subartist(&self) -> Option<&str> {
+        self.header(HeaderKind::Subartist)
+    }
+
+    pub fn set_subartist(&mut self, subartist: &str) {
+        self.set_header(HeaderKind::Subartist, subartist);
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        self.get_header(HeaderKind::Bpm)?.parse()
+    }
+
+    pub fn set_bpm(&mut self, bpm: f64) {
+        self.set_header(HeaderKind::Bpm, bpm);
+    }
+
+    pub fn play_level(&self) -> Option<u32> {
+        self.get_header(HeaderKind::PlayLevel)?.parse()
+    }
+
+    pub fn set_play_level(&mut self, play_level: u32) {
+        self.set_header(HeaderKind::PlayLevel, play_level);
+    }
+
+    pub fn difficulty(&self) -> Option<u32> {
+        self.get_header(HeaderKind::Difficulty)?.parse()
+    }
+
+    pub fn set_difficulty(&mut self, difficulty: u32) {
+        self.set_header(HeaderKind::Difficulty, difficulty);
+    }
+
+    pub fn rank(&self) -> Option<u32> {
+        self.get_header(HeaderKind::Rank)?.parse()
+    }
+
+    pub fn set_rank(&mut self, rank: u32) {
+        self.set_header(HeaderKind::Rank, rank);
+    }
+
+    pub fn total(&self) -> Option<f64> {
+        self.get_header(HeaderKind::Total)?.parse()
+    }
+
+    pub fn set_total(&mut self, total: f64) {
+        self.set_header(HeaderKind::Total, total);
+    }
+
+    pub fn stage_file(&self) -> Option<&str> {
+        self.header(HeaderKind::StageFile)
+    }
+
+    pub fn set_stage_file(&mut self, stage_file: &str) {
+        self.set_header(HeaderKind::StageFile, stage_file);
+    }
+
+    pub fn banner(&self) -> Option<&str> {
+        self.header(HeaderKind::Banner)
+    }
+
+    pub fn set_banner(&mut self, banner: &str) {
+        self.set_header(HeaderKind::Banner, banner);
+    }
+
+    pub fn back_bmp(&self) -> Option<&str> {
+        self.header(HeaderKind::BackBmp)
+    }
+
+    pub fn set_back_bmp(&mut self, back_bmp: &str) {
+        self.set_header(HeaderKind::BackBmp, back_bmp);
+    }
+
+    pub fn preview(&self) -> Option<&str> {
+        self.header(HeaderKind::Preview)
+    }
+
+    pub fn set_preview(&mut self, preview: &str) {
+        self.set_header(HeaderKind::Preview, preview);
+    }
+
+    /// The keysound ID that marks the end of a long note.
+    pub fn ln_obj(&self) -> Option<u64> {
+        as_id(self.header(HeaderKind::LnObj)?).ok()
+    }
+
+    pub fn set_ln_obj(&mut self, id: u64) {
+        self.set_header(HeaderKind::LnObj, as_str(id));
+    }
+
+    pub fn ln_type(&self) -> Option<u32> {
+        self.get_header(HeaderKind::LnType)?.parse()
+    }
+
+    pub fn set_ln_type(&mut self, ln_type: u32) {
+        self.set_header(HeaderKind::LnType, ln_type);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::SHIFT_JIS;
+
+    use crate::{
+        resource::{SILENT_PLACEHOLDER, write_silent_placeholder},
+        testing::TempFolder,
+    };
+
+    use super::*;
+
+    fn corpus_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/corpus")
+    }
+
+    #[test]
+    fn test_corpus_round_trip() {
+        let mut charts = 0;
+
+        for entry in fs::read_dir(corpus_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            let bytes = fs::read(&path).unwrap();
+
+            let bms = BMSFile::from_bytes(&path, &bytes);
+
+            assert_eq!(
+                bms.to_bytes().unwrap(),
+                bytes,
+                "{} did not round trip.",
+                path.display()
+            );
+
+            charts += 1;
+        }
+
+        assert!(charts > 0, "The round trip corpus is empty.");
+    }
+
+    #[test]
+    fn test_invalid_line_keeps_the_rest() {
+        let path = corpus_dir().join("invalid_utf8_line.bms");
+        let bms = BMSFile::from_path(&path).unwrap();
+
+        // Everything after the line that isn't UTF-8 is still read
+        assert_eq!(bms.lines().len(), 5);
+        assert!(bms.has_keysound(as_id("02").unwrap()));
+        assert_eq!(bms.notes().count(), 1);
+    }
+
+    #[test]
+    fn test_merge_only_touches_modified_lines() {
+        let path = corpus_dir().join("crlf_shift_jis.bme");
+        let bytes = fs::read(&path).unwrap();
+
+        let mut bms = BMSFile::from_bytes(&path, &bytes);
+
+        let summary = bms.merge_keysounds(
+            as_id("0A").unwrap(),
+            &[as_id("0B").unwrap()],
+            &ReplacePolicy::default(),
+        );
+        assert_eq!(summary, vec![(as_id("0B").unwrap(), 2)]);
+
+        let new_bytes = bms.to_bytes().unwrap();
+
+        let (old_text, _) = TextEncoding::decode(&bytes);
+        let (new_text, encoding) = TextEncoding::decode(&new_bytes);
+
+        assert_eq!(encoding, bms.encoding);
+
+        let old_lines: Vec<&str> = old_text.split_inclusive('\n').collect();
+        let new_lines: Vec<&str> = new_text.split_inclusive('\n').collect();
+
+        // The #WAV0b definition is gone, and only the two notes that used it are rewritten
+        let removed: Vec<&&str> = old_lines
+            .iter()
+            .filter(|line| !new_lines.contains(line))
+            .collect();
+        let added: Vec<&&str> = new_lines
+            .iter()
+            .filter(|line| !old_lines.contains(line))
+            .collect();
+
+        assert_eq!(
+            removed,
+            vec![
+                &"#WAV0b snare.wav \r\n",
+                &"#00101:0a000b00\r\n",
+                &"#00251:00000b0a\r\n"
+            ]
+        );
+        assert_eq!(added, vec![&"#00101:0A000A00\r\n", &"#00251:00000A0A\r\n"]);
+    }
+
+    #[test]
+    fn test_definitions_keep_their_position() {
+        let path = corpus_dir().join("interleaved_definitions.bms");
+        let bytes = fs::read(&path).unwrap();
+
+        let mut bms = BMSFile::from_bytes(&path, &bytes);
+
+        assert_eq!(bms.find_keysound(as_id("01").unwrap()), Some(2));
+        assert_eq!(bms.find_keysound(as_id("03").unwrap()), Some(6));
+        assert_eq!(bms.find_keysound(as_id("04").unwrap()), Some(10));
+
+        bms.retain_keysounds(|ks| ks.keysound_id() != as_id("02").unwrap());
+
+        let expected: Vec<u8> = String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with("#WAV02"))
+            .flat_map(|line| [line, "\n"])
+            .collect::<String>()
+            .into_bytes();
+
+        assert_eq!(bms.to_bytes().unwrap(), expected);
+
+        // The index follows the definitions that moved up
+        assert_eq!(bms.find_keysound(as_id("02").unwrap()), None);
+        assert_eq!(bms.find_keysound(as_id("03").unwrap()), Some(5));
+        assert_eq!(bms.find_keysound(as_id("04").unwrap()), Some(9));
+    }
+
+    #[test]
+    fn test_headers_are_edited_in_place() {
+        let path = corpus_dir().join("crlf_shift_jis.bme");
+        let bytes = fs::read(&path).unwrap();
+
+        let mut bms = BMSFile::from_bytes(&path, &bytes);
+
+        assert_eq!(bms.player(), Some(1));
+        assert_eq!(bms.genre(), Some("ピアノ・コア"));
+        assert_eq!(bms.title(), Some("白い雪 [ANOTHER]"));
+        assert_eq!(bms.bpm(), Some(174.0));
+        assert_eq!(bms.play_level(), Some(11));
+        assert_eq!(bms.total(), None);
+
+        let title_index = bms
+            .lines
+            .iter()
+            .position(|line| {
+                line.as_header()
+                    .is_some_and(|h| h.kind() == HeaderKind::Title)
+            })
+            .unwrap();
+
+        bms.set_title("白い雪 [INSANE]");
+        bms.set_bpm(174.5);
+        bms.set_total(320.0);
+
+        assert_eq!(bms.title(), Some("白い雪 [INSANE]"));
+        assert_eq!(bms.bpm(), Some(174.5));
+        assert_eq!(bms.total(), Some(320.0));
+        assert_eq!(bms.lines[title_index].to_string(), "#TITLE 白い雪 [INSANE]");
+
+        // New headers go after the last existing one
+        let rank_index = bms
+            .lines
+            .iter()
+            .position(|line| {
+                line.as_header()
+                    .is_some_and(|h| h.kind() == HeaderKind::Rank)
+            })
+            .unwrap();
+
+        assert_eq!(bms.lines[rank_index + 1].to_string(), "#TOTAL 320");
+
+        let (text, _) = TextEncoding::decode(&bms.to_bytes().unwrap());
+        assert!(text.contains("#GENRE ピアノ・コア   \r\n#TITLE 白い雪 [INSANE]\r\n#ARTIST"));
+
+        // Added headers end the same way as the rest of the chart
+        assert!(text.contains("\r\n#TOTAL 320\r\n"));
+    }
+
+    #[test]
+    fn test_merge_follows_policy() {
+        let text = "#LNOBJ 0B\n#WAV0A a.wav\n#WAV0B b.wav\n#WAV0C c.wav\n#00101:0B00\n#00111:0A0B\n#00104:0B\n";
+
+        let mut bms = BMSFile::from_bytes(Path::new("policy.bms"), text.as_bytes());
+
+        let no_bgm = ReplacePolicy {
+            bgm: false,
+            ..Default::default()
+        };
+
+        // 0A has normal notes, so the long note ending keeps 0B and the BGM object is skipped
+        let summary = bms.merge_keysounds(10, &[10, 11], &no_bgm);
+        assert_eq!(summary, vec![(11, 0)]);
+        assert_eq!(bms.to_text(), text);
+
+        // The ending still uses 0B, so its definition stays, and the BGA object is untouched
+        bms.merge_keysounds(10, &[10, 11], &ReplacePolicy::default());
+        assert_eq!(
+            bms.to_text(),
+            "#LNOBJ 0B\n#WAV0A a.wav\n#WAV0B b.wav\n#WAV0C c.wav\n#00101:0A00\n#00111:0A0B\n#00104:0B\n"
+        );
+
+        // Normal notes never become endings by being merged into the #LNOBJ
+        bms.merge_keysounds(11, &[10], &ReplacePolicy::default());
+        assert_eq!(bms.count_keysound_uses(10), 1);
+
+        // 0C has no notes, so #LNOBJ can follow the ending
+        bms.merge_keysounds(12, &[11], &ReplacePolicy::default());
+        assert!(!bms.has_keysound(11));
+        assert_eq!(bms.ln_obj(), Some(12));
+        assert_eq!(
+            bms.to_text(),
+            "#LNOBJ 0C\n#WAV0A a.wav\n#WAV0C c.wav\n#00101:0C00\n#00111:0A0C\n#00104:0B\n"
+        );
+
+        // Without ln_obj, the ending and its definition stay where #LNOBJ points
+        let mut bms = BMSFile::from_bytes(Path::new("policy.bms"), text.as_bytes());
+        let no_ln_obj = ReplacePolicy {
+            ln_obj: false,
+            ..Default::default()
+        };
+
+        bms.merge_keysounds(12, &[11], &no_ln_obj);
+        assert!(bms.has_keysound(11));
+        assert_eq!(bms.ln_obj(), Some(11));
+        assert_eq!(bms.count_keysound_uses(11), 1);
+    }
+
+    #[test]
+    fn test_undefined_keysounds() {
+        let text =
+            "#WAV0A a.wav\n#00102:0.5\n#00111:0A0C\n#00251:0C00\n#00104:0D\n#001D1:0E\n#00301:0Z\n";
+
+        let bms = BMSFile::from_bytes(Path::new("undefined.bms"), text.as_bytes());
+
+        assert_eq!(
+            bms.get_undefined_keysounds(),
+            vec![
+                UndefinedKeysound {
+                    keysound_id: as_id("0C").unwrap(),
+                    locations: vec![
+                        (1, Channel::P1Visible(1), 5.0),
+                        (2, Channel::P1LongNote(1), 6.0)
+                    ],
+                },
+                UndefinedKeysound {
+                    keysound_id: as_id("0Z").unwrap(),
+                    locations: vec![(3, Channel::Bgm, 10.0)],
+                },
+            ]
+        );
+
+        // Measure 001 is half as long, so everything after it is 2 beats earlier
+        assert_eq!(
+            bms.get_undefined_keysounds()[0].to_string(),
+            "0C (2 objects): #00111 (beat 5), #00251 (beat 6)"
+        );
+    }
+
+    #[test]
+    fn test_branch_aware_unused_keysounds() {
+        let text = "#WAV01 a.wav
+#WAV02 b.wav
+#WAV03 c.wav
+#RANDOM 2
+#IF 1
+#WAV02 b1.wav
+#WAV03 c1.wav
+#00111:0102
+#ELSE
+#WAV03 c2.wav
+#00112:04
+#ENDIF
+#IF 2
+#00113:03
+#ENDIF
+#WAV04 d.wav
+";
+        let mut bms = BMSFile::from_bytes(Path::new("random.bms"), text.as_bytes());
+
+        let usage: Vec<(String, String)> = bms
+            .keysound_usage()
+            .iter()
+            .map(|usage| {
+                let branches = usage
+                    .branches
+                    .iter()
+                    .map(|branch| bms.describe_branch(branch))
+                    .collect::<Vec<String>>()
+                    .join("; ");
+
+                (usage.keysound.to_string(), branches)
+            })
+            .collect();
+
+        // 02 and 03 are replaced in branch 1, and 03 is only used in branch 2
+        assert_eq!(
+            usage,
+            [
+                ("#WAV01 a.wav", "#RANDOM 2 = 1"),
+                ("#WAV02 b.wav", ""),
+                ("#WAV03 c.wav", ""),
+                ("#WAV02 b1.wav", "#RANDOM 2 = 1"),
+                ("#WAV03 c1.wav", ""),
+                ("#WAV03 c2.wav", "#RANDOM 2 = 2"),
+                ("#WAV04 d.wav", "#RANDOM 2 = 2"),
+            ]
+            .map(|(keysound, branches)| (keysound.to_string(), branches.to_string()))
+        );
+
+        let removed = bms.remove_unused_keysounds();
+
+        assert_eq!(
+            removed
+                .iter()
+                .map(|keysound| keysound.to_string())
+                .collect::<Vec<String>>(),
+            ["#WAV02 b.wav", "#WAV03 c.wav", "#WAV03 c1.wav"]
+        );
+        assert!(bms.to_text().contains("#WAV02 b1.wav\n#00111:0102"));
+        assert!(bms.get_unused_keysounds().is_empty());
+    }
+
+    #[test]
+    fn test_expand_random() {
+        let text = "#TITLE r
+#WAV01 a.wav
+#RANDOM 2
+#IF 1
+#WAV02 b.wav
+#00111:02
+#RANDOM 2
+#IF 2
+#00112:02
+#ENDIF
+#ENDRANDOM
+#ELSE
+#00113:01
+#ENDIF
+#00114:01
+";
+        let bms = BMSFile::from_bytes(Path::new("random.bms"), text.as_bytes());
+        let branches = bms.branches();
+
+        // The inner #RANDOM only takes part when the outer one picks 1
+        let outcomes = branches.outcomes(10).unwrap();
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::from([(2, 1), (6, 1)]),
+                Outcome::from([(2, 1), (6, 2)]),
+                Outcome::from([(2, 2)]),
+            ]
+        );
+        assert!(branches.outcomes(2).is_none());
+
+        let expanded = bms.expand(&outcomes[1], Path::new("random_1-2.bms"));
+        assert_eq!(
+            expanded.to_text(),
+            "#TITLE r\n#WAV01 a.wav\n#WAV02 b.wav\n#00111:02\n#00112:02\n#00114:01\n"
+        );
+
+        let expanded = bms.expand(&outcomes[2], Path::new("random_2.bms"));
+        assert_eq!(
+            expanded.to_text(),
+            "#TITLE r\n#WAV01 a.wav\n#00113:01\n#00114:01\n"
+        );
+
+        let seeded = branches.outcome_from_seed(7);
+        assert_eq!(branches.outcome_from_seed(7), seeded);
+        assert!(outcomes.contains(&seeded));
+    }
+
+    #[test]
+    fn test_edit_measure_lengths() {
+        let text = "#WAV01 a.wav\r\n#00102:0.75\r\n#00111:01\r\n#00311:01\r\n#00102:0.5\r\n";
+        let mut bms = BMSFile::from_bytes(Path::new("lengths.bms"), text.as_bytes());
+
+        // The later line wins
+        let lengths = bms.measure_lengths();
+        assert_eq!(lengths.get(1), 0.5);
+
+        assert!(bms.set_measure_length(2, 0.0).is_err());
+        assert_eq!(bms.to_text(), text);
+
+        bms.set_measure_length(1, 1.5).unwrap();
+        bms.set_measure_length(3, 0.25).unwrap();
+        assert_eq!(
+            bms.to_text(),
+            "#WAV01 a.wav\r\n#00102:1.5\r\n#00111:01\r\n#00302:0.25\r\n#00311:01\r\n#00102:1.5\r\n"
+        );
+
+        assert_eq!(bms.remove_measure_length(1), 2);
+        assert_eq!(
+            bms.measure_lengths().iter().collect::<Vec<_>>(),
+            [(3, 0.25)]
+        );
+    }
+
+    #[test]
+    fn test_missing_keysounds() {
+        let folder = TempFolder::new("missing");
+
+        fs::write(folder.join("kick.ogg"), b"").unwrap();
+
+        let text = "#WAV01 kick.wav\n#WAV02 snare.wav\n#WAV03 hat.wav\n#00111:0102\n#00101:0202\n";
+        let mut bms = BMSFile::from_bytes(&folder.join("missing.bms"), text.as_bytes());
+
+        // kick.wav is found as kick.ogg, the way players would
+        let missing = bms.get_missing_keysounds();
+        assert_eq!(
+            missing
+                .iter()
+                .map(|missing| missing.to_string())
+                .collect::<Vec<String>>(),
+            vec!["02 snare.wav (3 objects)", "03 hat.wav (0 objects)"]
+        );
+
+        assert_eq!(bms.remove_keysound(2), 3);
+        assert_eq!(
+            bms.to_text(),
+            "#WAV01 kick.wav\n#WAV03 hat.wav\n#00111:0100\n#00101:0000\n"
+        );
+
+        write_silent_placeholder(&folder.join(SILENT_PLACEHOLDER)).unwrap();
+        bms.get_keysound_mut(3)
+            .unwrap()
+            .set_keysound_file(SILENT_PLACEHOLDER.to_string());
+        assert!(bms.get_missing_keysounds().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_line_keeps_shift_jis_filenames() {
+        let folder = TempFolder::new("invalid-line");
+
+        for file in ["ピアノ_01.wav", "ドラム.ogg"] {
+            fs::write(folder.join(file), b"").unwrap();
+        }
+
+        let text = "#TITLE ホワイトアウト\r\n#WAV01 ピアノ_01.wav\r\n#WAV02 ドラム.wav\r\n";
+        let mut bytes = SHIFT_JIS.encode(text).0.into_owned();
+        bytes.extend_from_slice(b"#GENRE \x81\xFF\xFE\r\n#00111:0102\r\n");
+
+        let bms = BMSFile::from_bytes(&folder.join("chart.bms"), &bytes);
+
+        assert_eq!(bms.encoding().encoding(), SHIFT_JIS);
+        assert_eq!(bms.get_keysound(2).unwrap().keysound_file(), "ドラム.wav");
+        assert!(bms.get_missing_keysounds().is_empty());
+        assert_eq!(
+            bms.diagnostics()
+                .iter()
+                .map(|diagnostic| (diagnostic.line, diagnostic.column))
+                .collect::<Vec<_>>(),
+            [(4, 8)]
+        );
+
+        let files = classify_files(&folder, &[&bms]).unwrap();
+        assert!(
+            files
+                .iter()
+                .all(|file| file.status == FileStatus::Referenced)
+        );
+
+        assert_eq!(bms.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_diff() {
+        let text = "#TITLE diff\n#WAV01 a.wav\n#WAV02 b.wav\n#00111:0102\n#00112:01\n";
+
+        let original = BMSFile::from_bytes(Path::new("diff.bms"), text.as_bytes());
+        let mut bms = original.clone();
+
+        assert_eq!(original.diff(&bms), "");
+
+        bms.merge_keysounds(
+            as_id("01").unwrap(),
+            &[as_id("02").unwrap()],
+            &ReplacePolicy::default(),
+        );
+
+        assert_eq!(
+            original.diff(&bms),
+            "--- diff.bms\n+++ diff.bms\n@@ -1,5 +1,4 @@\n #TITLE diff\n #WAV01 a.wav\n\
+            -#WAV02 b.wav\n-#00111:0102\n+#00111:0101\n #00112:01\n"
+        );
+    }
+
+    #[test]
+    fn test_save_detects_external_changes() {
+        let folder = TempFolder::new("save");
+
+        let path = folder.join("chart.bms");
+        fs::write(&path, "#TITLE a\n#WAV01 a.wav\n#WAV02 b.wav\n#00111:0102\n").unwrap();
+
+        let mut bms = BMSFile::from_path(&path).unwrap();
+        bms.merge_keysounds(
+            as_id("01").unwrap(),
+            &[as_id("02").unwrap()],
+            &ReplacePolicy::default(),
+        );
+
+        // Another editor changes the title, keeping the file the same size
+        fs::write(&path, "#TITLE b\n#WAV01 a.wav\n#WAV02 b.wav\n#00111:0102\n").unwrap();
+
+        assert!(bms.changed_on_disk().unwrap());
+        assert!(matches!(bms.save(), Err(BmsError::Conflict { .. })));
+
+        bms.merge_external_changes().unwrap();
+
+        // The merged chart starts from the other editor's version
+        assert_eq!(
+            bms.as_loaded().to_text(),
+            "#TITLE b\n#WAV01 a.wav\n#WAV02 b.wav\n#00111:0102\n"
+        );
+
+        bms.save().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "#TITLE b\n#WAV01 a.wav\n#00111:0101\n"
+        );
+
+        // Saving again is fine now that the chart matches the file
+        assert!(!bms.changed_on_disk().unwrap());
+        bms.set_title("c");
+        bms.save().unwrap();
+
+        // Overlapping edits can't be merged, and leave the chart as it was
+        fs::write(&path, "#TITLE d\n#WAV01 a.wav\n#00111:0101\n").unwrap();
+        bms.set_title("e");
+
+        assert!(matches!(
+            bms.merge_external_changes(),
+            Err(BmsError::MergeConflict { conflicts: 1, .. })
+        ));
+        assert_eq!(bms.title(), Some("e"));
+
+        bms.overwrite().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "#TITLE e\n#WAV01 a.wav\n#00111:0101\n"
+        );
+
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(&folder).unwrap().count(), 1);
+    }
+}
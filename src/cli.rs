@@ -0,0 +1,1360 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use bmsjoin::{
+    BMSFile, BackupStore, BmsError, FileRename, History, Keysound, Modifications, Quarantine,
+    QuarantinedFile, ReplacePolicy, SongPackage, as_keysound_id, as_str,
+    backup::DEFAULT_RETENTION,
+    rename_files,
+    resource::{FileResolver, SILENT_PLACEHOLDER, write_silent_placeholder},
+};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    confirm, delete_audio_file, get_string, keysounds_playing, load_history, print_backups,
+    print_diff, print_quarantined_files, print_step, save_checked,
+};
+
+/// Exit status when `check` finds keysounds that are used but never defined.
+const EXIT_UNDEFINED_KEYSOUNDS: u8 = 3;
+
+/// Exit status when `missing` finds keysounds whose audio file doesn't exist.
+const EXIT_MISSING_AUDIO: u8 = 4;
+
+/// The most charts `expand` writes without being given an outcome.
+const MAX_VARIANTS: usize = 1000;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Merge, replace and prune keysounds in BMS charts.",
+    args_conflicts_with_subcommands = true,
+    after_help = "Run with only a chart to edit it through the interactive menu.\n\n\
+        Exit status is 0 on success, 1 if the chart couldn't be read or saved or a change was \
+        declined, 2 for invalid arguments, 3 if `check` found undefined keysounds and 4 if \
+        `missing` found keysounds without audio."
+)]
+pub struct Cli {
+    /// The chart to edit interactively.
+    pub chart: Option<PathBuf>,
+
+    /// How many backups of each file to keep. 0 keeps every backup.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_RETENTION, global = true)]
+    pub keep_backups: usize,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Replace keysounds with another one and remove their definitions.
+    Replace {
+        chart: PathBuf,
+
+        /// The keysound to replace the others with.
+        #[arg(long, value_name = "ID", value_parser = parse_id)]
+        into: u64,
+
+        /// The keysounds to replace, separated by commas (eg. 0B,0C).
+        #[arg(required = true, value_name = "IDS", value_delimiter = ',', value_parser = parse_id)]
+        ids: Vec<u64>,
+
+        /// Also replace them in the other charts of the song that define them with the same files.
+        #[arg(long)]
+        all_charts: bool,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// Merge keysounds into whichever of them is used the most.
+    Merge {
+        chart: PathBuf,
+
+        /// The keysounds to merge, separated by commas (eg. 0A,0B,0C).
+        #[arg(required = true, value_name = "IDS", value_delimiter = ',', value_parser = parse_id)]
+        ids: Vec<u64>,
+
+        /// The keysound to keep instead of the most used one.
+        #[arg(long, value_name = "ID", value_parser = parse_id)]
+        keep: Option<u64>,
+
+        /// Also merge them in the other charts of the song that define them with the same files.
+        #[arg(long)]
+        all_charts: bool,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// Remove keysound definitions that no note uses.
+    PruneKeysounds {
+        chart: PathBuf,
+
+        /// Also delete the audio files of the removed keysounds.
+        #[arg(long)]
+        delete_files: bool,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// Delete audio files in the chart's folder that no keysound of any chart refers to.
+    PruneFiles {
+        chart: PathBuf,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// Apply a JSON file of modifications. Nothing is changed if any of them fails.
+    Apply {
+        chart: PathBuf,
+
+        /// The modifications to apply.
+        modifications: PathBuf,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// List the backups of a file, or of every file in a song folder, and restore one of them.
+    Restore {
+        /// A chart or audio file, or a song folder.
+        path: PathBuf,
+
+        /// The number of the backup to restore, as listed. Asks if missing.
+        number: Option<usize>,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// List the files in the chart's folder and whether any chart refers to them.
+    Files { chart: PathBuf },
+
+    /// List, restore or purge the audio files removed from a song folder.
+    Quarantine {
+        #[command(subcommand)]
+        action: QuarantineAction,
+    },
+
+    /// Undo the last change made to a chart, bringing back any files it removed.
+    Undo {
+        chart: PathBuf,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// Redo the last undone change to a chart.
+    Redo {
+        chart: PathBuf,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// List the changes that can be undone or redone.
+    History { chart: PathBuf },
+
+    /// List the keysounds that are used but not defined.
+    Check { chart: PathBuf },
+
+    /// List every keysound definition and the #RANDOM or #SWITCH branches that use it.
+    Keysounds { chart: PathBuf },
+
+    /// Write a chart without #RANDOM or #SWITCH blocks for every outcome of them.
+    Expand {
+        chart: PathBuf,
+
+        /// The folder to write the charts to.
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+
+        /// Only write the outcome picked from this seed.
+        #[arg(long, conflicts_with = "values")]
+        seed: Option<u64>,
+
+        /// Only write the outcome with these values, given to the blocks in the order they are
+        /// reached (eg. 2,1).
+        #[arg(long, value_name = "VALUES", value_delimiter = ',')]
+        values: Vec<u64>,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// List the keysounds whose audio file doesn't exist, and optionally fix them.
+    Missing {
+        chart: PathBuf,
+
+        /// Point a keysound at another file (eg. 0A=piano.ogg). Can be given more than once.
+        #[arg(long, value_name = "ID=FILE", value_parser = parse_remap)]
+        remap: Vec<(u64, String)>,
+
+        /// Point the other missing keysounds at a generated silent file.
+        #[arg(long, conflicts_with = "remove")]
+        silence: bool,
+
+        /// Remove the other missing keysounds along with their notes.
+        #[arg(long)]
+        remove: bool,
+
+        #[command(flatten)]
+        options: Options,
+    },
+}
+
+impl CliCommand {
+    fn options_mut(&mut self) -> Option<&mut Options> {
+        match self {
+            CliCommand::Replace { options, .. }
+            | CliCommand::Merge { options, .. }
+            | CliCommand::PruneKeysounds { options, .. }
+            | CliCommand::PruneFiles { options, .. }
+            | CliCommand::Apply { options, .. }
+            | CliCommand::Restore { options, .. }
+            | CliCommand::Undo { options, .. }
+            | CliCommand::Redo { options, .. }
+            | CliCommand::Expand { options, .. }
+            | CliCommand::Missing { options, .. }
+            | CliCommand::Quarantine {
+                action:
+                    QuarantineAction::Restore { options, .. } | QuarantineAction::Purge { options, .. },
+            } => Some(options),
+            CliCommand::Files { .. }
+            | CliCommand::Quarantine { .. }
+            | CliCommand::History { .. }
+            | CliCommand::Check { .. }
+            | CliCommand::Keysounds { .. } => None,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum QuarantineAction {
+    /// List the removed files, oldest first.
+    List {
+        /// A chart, or the song folder it is in.
+        path: PathBuf,
+    },
+
+    /// Move removed files back to where they were.
+    Restore {
+        /// A chart, or the song folder it is in.
+        path: PathBuf,
+
+        /// The numbers of the files to restore, as listed.
+        #[arg(required = true)]
+        numbers: Vec<usize>,
+
+        #[command(flatten)]
+        options: Options,
+    },
+
+    /// Permanently delete removed files.
+    Purge {
+        /// A chart, or the song folder it is in.
+        path: PathBuf,
+
+        /// The numbers of the files to purge, as listed.
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        numbers: Vec<usize>,
+
+        /// Purge every removed file.
+        #[arg(long)]
+        all: bool,
+
+        #[command(flatten)]
+        options: Options,
+    },
+}
+
+#[derive(Args, Clone, Copy)]
+pub struct Options {
+    /// Answer yes to every confirmation.
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Show what would change without writing or deleting anything.
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// How many backups of each file to keep, passed down from [`Cli::keep_backups`].
+    #[arg(skip = DEFAULT_RETENTION)]
+    keep_backups: usize,
+}
+
+impl Options {
+    /// The backups of the song folder a file is in.
+    fn backups(&self, file: &Path) -> BackupStore {
+        BackupStore::for_chart(file).with_retention(self.keep_backups)
+    }
+}
+
+/// Parses a two character keysound ID such as `0A`.
+pub(crate) fn parse_id(id: &str) -> Result<u64, String> {
+    as_keysound_id(id.trim()).ok_or_else(|| format!("{} is not a two character base-36 ID", id))
+}
+
+/// Parses a keysound and the file it should play, such as `0A=piano.ogg`.
+fn parse_remap(remap: &str) -> Result<(u64, String), String> {
+    let (id, file) = remap
+        .split_once('=')
+        .ok_or_else(|| format!("{} is not of the form ID=FILE", remap))?;
+
+    if file.trim().is_empty() {
+        return Err(format!("{} doesn't name a file", remap));
+    }
+
+    Ok((parse_id(id)?, file.trim().to_string()))
+}
+
+/// Why a command stopped without finishing.
+enum Failure {
+    Error(String),
+    Declined,
+    UndefinedKeysounds,
+    MissingAudio,
+}
+
+impl From<BmsError> for Failure {
+    fn from(error: BmsError) -> Self {
+        Failure::Error(error.to_string())
+    }
+}
+
+pub fn run(mut command: CliCommand, keep_backups: usize) -> ExitCode {
+    if let Some(options) = command.options_mut() {
+        options.keep_backups = keep_backups;
+    }
+
+    let result = match command {
+        CliCommand::Replace {
+            chart,
+            into,
+            ids,
+            all_charts,
+            options,
+        } => replace(&chart, into, &ids, all_charts, options),
+        CliCommand::Merge {
+            chart,
+            ids,
+            keep,
+            all_charts,
+            options,
+        } => merge(&chart, &ids, keep, all_charts, options),
+        CliCommand::PruneKeysounds {
+            chart,
+            delete_files,
+            options,
+        } => prune_keysounds(&chart, delete_files, options),
+        CliCommand::PruneFiles { chart, options } => prune_files(&chart, options),
+        CliCommand::Apply {
+            chart,
+            modifications,
+            options,
+        } => apply(&chart, &modifications, options),
+        CliCommand::Restore {
+            path,
+            number,
+            options,
+        } => restore(&path, number, options),
+        CliCommand::Files { chart } => files(&chart),
+        CliCommand::Quarantine { action } => quarantine(action),
+        CliCommand::Undo { chart, options } => undo(&chart, options),
+        CliCommand::Redo { chart, options } => redo(&chart, options),
+        CliCommand::History { chart } => history(&chart),
+        CliCommand::Check { chart } => check(&chart),
+        CliCommand::Keysounds { chart } => keysounds(&chart),
+        CliCommand::Expand {
+            chart,
+            output,
+            seed,
+            values,
+            options,
+        } => expand(&chart, &output, seed, &values, options),
+        CliCommand::Missing {
+            chart,
+            remap,
+            silence,
+            remove,
+            options,
+        } => missing(&chart, &remap, silence, remove, options),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Error(message)) => {
+            eprintln!("Error: {}", message);
+            ExitCode::FAILURE
+        }
+        Err(Failure::Declined) => {
+            eprintln!("Nothing was changed.");
+            ExitCode::FAILURE
+        }
+        Err(Failure::UndefinedKeysounds) => ExitCode::from(EXIT_UNDEFINED_KEYSOUNDS),
+        Err(Failure::MissingAudio) => ExitCode::from(EXIT_MISSING_AUDIO),
+    }
+}
+
+/// Joins IDs into a list such as `0B,0C`.
+fn join_ids(ids: &[u64]) -> String {
+    ids.iter()
+        .map(|id| as_str(*id))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn load(chart: &Path) -> Result<BMSFile, Failure> {
+    let bms = BMSFile::from_path(chart)?;
+
+    bms.diagnostics()
+        .iter()
+        .for_each(|diagnostic| eprintln!("Warning: {}", diagnostic));
+
+    Ok(bms)
+}
+
+/// Checks that every ID is defined in the chart.
+fn check_defined(bms: &BMSFile, ids: &[u64]) -> Result<(), Failure> {
+    let missing: Vec<String> = ids
+        .iter()
+        .filter(|id| !bms.has_keysound(**id))
+        .map(|id| as_str(*id))
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::Error(format!(
+            "{} isn't defined in {}",
+            missing.join(", "),
+            bms.path().display()
+        )))
+    }
+}
+
+/// Prints the changes made to the chart, then backs it up and saves it once the changes are
+/// confirmed, recording them in the history as `description`. Nothing is written on a dry run.
+/// Returns whether a step was recorded.
+fn save(
+    original: &BMSFile,
+    bms: &mut BMSFile,
+    history: &mut History,
+    description: &str,
+    options: Options,
+) -> Result<bool, Failure> {
+    if !print_diff(original, bms) {
+        return Ok(false);
+    }
+
+    if options.dry_run {
+        println!("Dry run: {} was not modified.", bms.path().display());
+        return Ok(false);
+    }
+
+    if !confirm("Apply these changes (y/n)? ", options.yes) {
+        return Err(Failure::Declined);
+    }
+
+    let Some(before) = save_checked(bms, &options.backups(bms.path()), options.yes)? else {
+        return Err(Failure::Declined);
+    };
+
+    history.record(description, &before, bms)?;
+
+    Ok(true)
+}
+
+/// Moves files into the quarantine after asking for confirmation. Fails if any of them
+/// couldn't be removed.
+///
+/// `removed` are the keysounds the files were removed for, which are noted in the quarantine's
+/// manifest. The files are added to a new step named `description`, or to the step that the
+/// same command just recorded if `description` is None.
+fn delete_files(
+    bms: &BMSFile,
+    files: &[PathBuf],
+    removed: &[Keysound],
+    history: &mut History,
+    description: Option<&str>,
+    options: Options,
+) -> Result<(), Failure> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    if options.dry_run {
+        files
+            .iter()
+            .for_each(|file| println!("Would delete {}", file.display()));
+
+        return Ok(());
+    }
+
+    files.iter().for_each(|file| println!("{}", file.display()));
+
+    let prompt = format!("Delete these {} files (y/n)? ", files.len());
+
+    if !confirm(&prompt, options.yes) {
+        return Err(Failure::Declined);
+    }
+
+    let mut quarantine = Quarantine::for_chart(bms.path())?;
+
+    if let Some(description) = description {
+        history.record_removal(description)?;
+    }
+
+    let failed = files
+        .iter()
+        .filter(|file| {
+            let keysounds = keysounds_playing(bms, removed, file);
+            !delete_audio_file(&mut quarantine, history, bms, file, &keysounds)
+        })
+        .count();
+
+    if failed > 0 {
+        return Err(Failure::Error(format!(
+            "{} files couldn't be deleted",
+            failed
+        )));
+    }
+
+    Ok(())
+}
+
+/// Makes the same merge in the other charts of the song that define the keysounds with the
+/// same files as `original`, or names them if `all_charts` isn't set. Returns the charts as
+/// they were changed.
+fn merge_in_siblings(
+    original: &BMSFile,
+    target: u64,
+    ids: &[u64],
+    all_charts: bool,
+    description: &str,
+    options: Options,
+) -> Result<Vec<BMSFile>, Failure> {
+    let package = SongPackage::for_chart(original.path())?;
+
+    let mut shared_ids = ids.to_vec();
+    shared_ids.push(target);
+
+    let siblings = package.charts_sharing_keysounds(original, &shared_ids);
+
+    if !all_charts {
+        siblings.iter().for_each(|sibling| {
+            println!(
+                "Note: {} defines the same keysounds and was left unchanged (see --all-charts)",
+                sibling.path().display()
+            )
+        });
+
+        return Ok(Vec::new());
+    }
+
+    let mut changed_siblings = Vec::new();
+
+    for sibling in siblings {
+        let mut changed = sibling.clone();
+        changed.merge_keysounds(target, ids, &ReplacePolicy::default());
+
+        save(
+            sibling,
+            &mut changed,
+            &mut load_history(sibling.path()),
+            description,
+            options,
+        )?;
+
+        changed_siblings.push(changed);
+    }
+
+    Ok(changed_siblings)
+}
+
+/// Finds the files of removed keysounds that no chart of the song uses once `changed` are
+/// saved.
+fn orphaned_files(changed: &[&BMSFile], removed: &[Keysound]) -> Result<Vec<PathBuf>, Failure> {
+    let Some(first) = changed.first() else {
+        return Ok(Vec::new());
+    };
+
+    let mut package = SongPackage::for_chart(first.path())?;
+
+    changed
+        .iter()
+        .for_each(|chart| package.update((*chart).clone()));
+
+    Ok(package.get_orphaned_files(removed))
+}
+
+fn replace(
+    chart: &Path,
+    into: u64,
+    ids: &[u64],
+    all_charts: bool,
+    options: Options,
+) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let original = bms.clone();
+
+    check_defined(&bms, &[into])?;
+    check_defined(&bms, ids)?;
+
+    let summary = bms.merge_keysounds(into, ids, &ReplacePolicy::default());
+
+    summary.iter().for_each(|(old_id, replaced)| {
+        println!(
+            "Replaced {} with {} ({} objects)",
+            as_str(*old_id),
+            as_str(into),
+            replaced
+        );
+    });
+
+    let description = format!("Replace {} with {}", join_ids(ids), as_str(into));
+
+    save(
+        &original,
+        &mut bms,
+        &mut load_history(chart),
+        &description,
+        options,
+    )?;
+
+    merge_in_siblings(&original, into, ids, all_charts, &description, options)?;
+
+    Ok(())
+}
+
+fn merge(
+    chart: &Path,
+    ids: &[u64],
+    keep: Option<u64>,
+    all_charts: bool,
+    options: Options,
+) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let original = bms.clone();
+
+    let mut ids = ids.to_vec();
+    ids.sort();
+    ids.dedup();
+
+    if ids.len() < 2 {
+        return Err(Failure::Error(
+            "At least two keysounds are needed for a merge".to_string(),
+        ));
+    }
+
+    check_defined(&bms, &ids)?;
+
+    let target = match keep {
+        Some(id) if ids.contains(&id) => id,
+        Some(id) => {
+            return Err(Failure::Error(format!(
+                "{} is not one of the merged keysounds",
+                as_str(id)
+            )));
+        }
+        None => bms
+            .most_used_keysound(&ids)
+            .expect("There is at least one keysound to merge."),
+    };
+
+    let merged_keysounds: Vec<Keysound> = ids
+        .iter()
+        .filter(|id| **id != target)
+        .filter_map(|id| bms.get_keysound(*id))
+        .cloned()
+        .collect();
+
+    let summary = bms.merge_keysounds(target, &ids, &ReplacePolicy::default());
+
+    summary.iter().for_each(|(old_id, replaced)| {
+        println!(
+            "Merged {} into {} ({} objects)",
+            as_str(*old_id),
+            as_str(target),
+            replaced
+        );
+    });
+
+    let merged_ids: Vec<u64> = ids.iter().copied().filter(|id| *id != target).collect();
+    let description = format!("Merge {} into {}", join_ids(&merged_ids), as_str(target));
+
+    let mut history = load_history(chart);
+
+    let recorded = save(&original, &mut bms, &mut history, &description, options)?;
+
+    let siblings = merge_in_siblings(&original, target, &ids, all_charts, &description, options)?;
+
+    let mut changed = vec![&bms];
+    changed.extend(&siblings);
+
+    delete_files(
+        &bms,
+        &orphaned_files(&changed, &merged_keysounds)?,
+        &merged_keysounds,
+        &mut history,
+        (!recorded).then_some(description.as_str()),
+        options,
+    )
+}
+
+fn prune_keysounds(chart: &Path, delete: bool, options: Options) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let original = bms.clone();
+
+    let unused_keysounds = bms.remove_unused_keysounds();
+
+    if unused_keysounds.is_empty() {
+        println!("No unused keysounds in {}.", bms.path().display());
+        return Ok(());
+    }
+
+    unused_keysounds
+        .iter()
+        .for_each(|keysound| println!("Removing {}", keysound));
+
+    let mut history = load_history(chart);
+    let description = "Remove unused keysounds";
+
+    let recorded = save(&original, &mut bms, &mut history, description, options)?;
+
+    if delete {
+        delete_files(
+            &bms,
+            &orphaned_files(&[&bms], &unused_keysounds)?,
+            &unused_keysounds,
+            &mut history,
+            (!recorded).then_some(description),
+            options,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn prune_files(chart: &Path, options: Options) -> Result<(), Failure> {
+    let bms = load(chart)?;
+
+    let unused_files = SongPackage::for_chart(chart)?.get_unused_files()?;
+
+    if unused_files.is_empty() {
+        println!("No unused files next to {}.", bms.path().display());
+        return Ok(());
+    }
+
+    delete_files(
+        &bms,
+        &unused_files,
+        &[],
+        &mut load_history(chart),
+        Some("Remove unused audio"),
+        options,
+    )
+}
+
+fn apply(chart: &Path, modifications_path: &Path, options: Options) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let modifications = Modifications::from_path(modifications_path)?;
+    let original = bms.clone();
+
+    modifications
+        .operations
+        .iter()
+        .enumerate()
+        .for_each(|(index, operation)| println!("{}. {}", index + 1, operation));
+
+    let renames = modifications.apply(&mut bms)?;
+
+    // Renamed files always come with a change to the chart
+    if !print_diff(&original, &bms) {
+        return Ok(());
+    }
+
+    let verb = if options.dry_run {
+        "Would rename"
+    } else {
+        "Will rename"
+    };
+
+    renames.iter().for_each(|rename| {
+        println!(
+            "{} {} to {}",
+            verb,
+            rename.from.display(),
+            rename.to.display()
+        )
+    });
+
+    if options.dry_run {
+        println!("Dry run: {} was not modified.", bms.path().display());
+        return Ok(());
+    }
+
+    if !confirm("Apply these changes (y/n)? ", options.yes) {
+        return Err(Failure::Declined);
+    }
+
+    // The files are renamed first, so that nothing is backed up or recorded until the whole
+    // batch has gone through
+    rename_files(&renames)?;
+
+    let backups = options.backups(bms.path());
+    let saved = save_checked(&mut bms, &backups, options.yes);
+
+    let Ok(Some(before)) = saved else {
+        // The chart on disk still refers to the old names
+        let reversed: Vec<FileRename> = renames.iter().rev().map(FileRename::reversed).collect();
+        rename_files(&reversed)?;
+
+        return match saved {
+            Err(e) => Err(e.into()),
+            _ => Err(Failure::Declined),
+        };
+    };
+
+    let description = format!("Apply {}", modifications_path.display());
+    load_history(chart).record(&description, &before, &bms)?;
+
+    Ok(())
+}
+
+fn restore(path: &Path, number: Option<usize>, options: Options) -> Result<(), Failure> {
+    let (backups, all_backups) = if path.is_dir() {
+        let backups = BackupStore::new(path);
+        let all_backups = backups.list_all()?;
+
+        (backups, all_backups)
+    } else {
+        let backups = options.backups(path);
+        let all_backups = backups.list(path)?;
+
+        (backups, all_backups)
+    };
+
+    if all_backups.is_empty() {
+        println!("No backups of {} were found.", path.display());
+        return Ok(());
+    }
+
+    let number = match number {
+        Some(number) => number,
+        None => {
+            print_backups(&all_backups);
+
+            if options.dry_run {
+                return Ok(());
+            }
+
+            print!("\nEnter the number of the backup to restore: ");
+            io::stdout().flush().expect("Unable to flush stdout.");
+
+            match get_string().trim().parse() {
+                Ok(number) => number,
+                Err(_) => return Err(Failure::Declined),
+            }
+        }
+    };
+
+    let backup = all_backups
+        .get(number.wrapping_sub(1))
+        .ok_or_else(|| Failure::Error(format!("There is no backup numbered {}", number)))?;
+
+    if options.dry_run {
+        println!(
+            "Would restore {} from {}",
+            backup.original().display(),
+            backup.timestamp()
+        );
+
+        return Ok(());
+    }
+
+    let prompt = format!(
+        "Restore {} from {} (y/n)? ",
+        backup.original().display(),
+        backup.timestamp()
+    );
+
+    if !confirm(&prompt, options.yes) {
+        return Err(Failure::Declined);
+    }
+
+    backups.restore(backup)?;
+
+    println!("Restored {}", backup.original().display());
+
+    Ok(())
+}
+
+fn files(chart: &Path) -> Result<(), Failure> {
+    let package = SongPackage::for_chart(chart)?;
+
+    package.classify_files()?.iter().for_each(|usage| {
+        let kind = usage
+            .kind
+            .map(|kind| kind.to_string())
+            .unwrap_or_else(|| "-".to_string());
+
+        println!(
+            "{:<12}  {:<5}  {}",
+            usage.status.to_string(),
+            kind,
+            usage.path.display()
+        );
+    });
+
+    Ok(())
+}
+
+fn quarantine(action: QuarantineAction) -> Result<(), Failure> {
+    match action {
+        QuarantineAction::List { path } => {
+            let quarantine = open_quarantine(&path)?;
+
+            if quarantine.files().is_empty() {
+                println!("No removed files in {}.", quarantine.dir().display());
+            } else {
+                print_quarantined_files(quarantine.files());
+            }
+
+            Ok(())
+        }
+        QuarantineAction::Restore {
+            path,
+            numbers,
+            options,
+        } => {
+            let mut quarantine = open_quarantine(&path)?;
+            let files = pick_quarantined_files(&quarantine, &numbers)?;
+
+            if options.dry_run {
+                files
+                    .iter()
+                    .for_each(|file| println!("Would restore {}", file.original.display()));
+
+                return Ok(());
+            }
+
+            for file in &files {
+                quarantine.restore(file)?;
+                println!("Restored {}", file.original.display());
+            }
+
+            Ok(())
+        }
+        QuarantineAction::Purge {
+            path,
+            numbers,
+            all,
+            options,
+        } => {
+            let mut quarantine = open_quarantine(&path)?;
+
+            let files = if all {
+                quarantine.files().to_vec()
+            } else {
+                pick_quarantined_files(&quarantine, &numbers)?
+            };
+
+            if files.is_empty() {
+                println!("No removed files in {}.", quarantine.dir().display());
+                return Ok(());
+            }
+
+            let verb = if options.dry_run {
+                "Would purge"
+            } else {
+                "Purging"
+            };
+
+            files
+                .iter()
+                .for_each(|file| println!("{} {}", verb, file.original.display()));
+
+            if options.dry_run {
+                return Ok(());
+            }
+
+            let prompt = format!("Permanently delete these {} files (y/n)? ", files.len());
+
+            if !confirm(&prompt, options.yes) {
+                return Err(Failure::Declined);
+            }
+
+            for file in &files {
+                quarantine.purge(file)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Opens the quarantine of a song folder, or of the folder a chart is in.
+fn open_quarantine(path: &Path) -> Result<Quarantine, Failure> {
+    let quarantine = if path.is_dir() {
+        Quarantine::new(path)?
+    } else {
+        Quarantine::for_chart(path)?
+    };
+
+    Ok(quarantine)
+}
+
+/// Looks up quarantined files by the numbers they are listed with.
+fn pick_quarantined_files(
+    quarantine: &Quarantine,
+    numbers: &[usize],
+) -> Result<Vec<QuarantinedFile>, Failure> {
+    numbers
+        .iter()
+        .map(|number| {
+            quarantine
+                .files()
+                .get(number.wrapping_sub(1))
+                .cloned()
+                .ok_or_else(|| {
+                    Failure::Error(format!("There is no removed file numbered {}", number))
+                })
+        })
+        .collect()
+}
+
+fn undo(chart: &Path, options: Options) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let mut history = load_history(chart);
+
+    let Some(step) = history
+        .position()
+        .checked_sub(1)
+        .map(|i| &history.steps()[i])
+    else {
+        println!("Nothing to undo for {}.", chart.display());
+        return Ok(());
+    };
+
+    if options.dry_run {
+        print_step("Would undo", step);
+        return Ok(());
+    }
+
+    let prompt = format!("Undo \"{}\" (y/n)? ", step.description);
+
+    if !confirm(&prompt, options.yes) {
+        return Err(Failure::Declined);
+    }
+
+    let mut quarantine = Quarantine::for_chart(chart)?;
+
+    if let Some(step) = history.undo(&mut bms, &mut quarantine, &options.backups(chart))? {
+        print_step("Undid", step);
+    }
+
+    Ok(())
+}
+
+fn redo(chart: &Path, options: Options) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let mut history = load_history(chart);
+
+    let Some(step) = history.steps().get(history.position()) else {
+        println!("Nothing to redo for {}.", chart.display());
+        return Ok(());
+    };
+
+    if options.dry_run {
+        print_step("Would redo", step);
+        return Ok(());
+    }
+
+    let prompt = format!("Redo \"{}\" (y/n)? ", step.description);
+
+    if !confirm(&prompt, options.yes) {
+        return Err(Failure::Declined);
+    }
+
+    let mut quarantine = Quarantine::for_chart(chart)?;
+
+    if let Some(step) = history.redo(&mut bms, &mut quarantine, &options.backups(chart))? {
+        print_step("Redid", step);
+    }
+
+    Ok(())
+}
+
+/// Lists the steps of a chart's history, oldest first, marking the ones that can be redone.
+fn history(chart: &Path) -> Result<(), Failure> {
+    let history = History::for_chart(chart)?;
+
+    if history.steps().is_empty() {
+        println!("No changes to {} have been recorded.", chart.display());
+        return Ok(());
+    }
+
+    history
+        .steps()
+        .iter()
+        .enumerate()
+        .for_each(|(index, step)| {
+            let state = if index < history.position() {
+                ""
+            } else {
+                "  (undone)"
+            };
+
+            println!("{:>3}. {}{}", index + 1, step.description, state);
+        });
+
+    Ok(())
+}
+
+fn check(chart: &Path) -> Result<(), Failure> {
+    let bms = load(chart)?;
+
+    let undefined = bms.get_undefined_keysounds();
+
+    if undefined.is_empty() {
+        println!(
+            "All keysounds used in {} are defined.",
+            bms.path().display()
+        );
+        return Ok(());
+    }
+
+    crate::print_undefined_keysounds(&undefined);
+
+    Err(Failure::UndefinedKeysounds)
+}
+
+fn keysounds(chart: &Path) -> Result<(), Failure> {
+    let bms = load(chart)?;
+
+    for usage in bms.keysound_usage() {
+        let branches = if usage.is_unused() {
+            "unused".to_string()
+        } else {
+            usage
+                .branches
+                .iter()
+                .map(|branch| bms.describe_branch(branch))
+                .collect::<Vec<String>>()
+                .join("; ")
+        };
+
+        println!("{} (line {}): {}", usage.keysound, usage.line + 1, branches);
+    }
+
+    Ok(())
+}
+
+fn expand(
+    chart: &Path,
+    output: &Path,
+    seed: Option<u64>,
+    values: &[u64],
+    options: Options,
+) -> Result<(), Failure> {
+    let bms = load(chart)?;
+    let branches = bms.branches();
+
+    let describe = |line: usize| {
+        format!(
+            "{} on line {}",
+            bms.lines()[line].to_string().trim(),
+            line + 1
+        )
+    };
+
+    let outcomes = if let Some(seed) = seed {
+        vec![branches.outcome_from_seed(seed)]
+    } else if !values.is_empty() {
+        let mut error = None;
+        let mut given = values.iter();
+
+        let outcome = branches.outcome_with(|line, choices| match given.next() {
+            Some(value) if choices.contains(*value) => *value,
+            Some(value) => {
+                error.get_or_insert(format!("{} can't pick {}", describe(line), value));
+                *value
+            }
+            None => {
+                error.get_or_insert(format!("No value was given for {}", describe(line)));
+                0
+            }
+        });
+
+        if let Some(error) = error {
+            return Err(Failure::Error(error));
+        }
+
+        if given.next().is_some() {
+            return Err(Failure::Error(format!(
+                "Only {} blocks are reached, but {} values were given",
+                outcome.len(),
+                values.len()
+            )));
+        }
+
+        vec![outcome]
+    } else {
+        branches.outcomes(MAX_VARIANTS).ok_or_else(|| {
+            Failure::Error(format!(
+                "{} has more than {} outcomes. Pick one with --seed or --values.",
+                bms.path().display(),
+                MAX_VARIANTS
+            ))
+        })?
+    };
+
+    if outcomes.iter().all(|outcome| outcome.is_empty()) {
+        println!("{} has no #RANDOM or #SWITCH blocks.", bms.path().display());
+        return Ok(());
+    }
+
+    let stem = chart.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = chart.extension().unwrap_or_default().to_string_lossy();
+
+    let mut variants: Vec<BMSFile> = outcomes
+        .iter()
+        .map(|outcome| {
+            let values = outcome
+                .values()
+                .map(|value| value.to_string())
+                .collect::<Vec<String>>()
+                .join("-");
+
+            bms.expand(
+                outcome,
+                &output.join(format!("{}_{}.{}", stem, values, extension)),
+            )
+        })
+        .collect();
+
+    if options.dry_run {
+        variants
+            .iter()
+            .for_each(|variant| println!("Would write {}", variant.path().display()));
+        return Ok(());
+    }
+
+    let existing = variants
+        .iter()
+        .filter(|variant| variant.path().exists())
+        .count();
+
+    if existing > 0
+        && !confirm(
+            &format!("Overwrite {} existing charts (y/n)? ", existing),
+            options.yes,
+        )
+    {
+        return Err(Failure::Declined);
+    }
+
+    fs::create_dir_all(output).map_err(|e| BmsError::io(output, e))?;
+
+    for variant in &mut variants {
+        variant.overwrite()?;
+        println!("Wrote {}", variant.path().display());
+    }
+
+    Ok(())
+}
+
+fn missing(
+    chart: &Path,
+    remap: &[(u64, String)],
+    silence: bool,
+    remove: bool,
+    options: Options,
+) -> Result<(), Failure> {
+    let mut bms = load(chart)?;
+    let original = bms.clone();
+
+    let missing = bms.get_missing_keysounds();
+
+    if remap.is_empty() && !silence && !remove {
+        if missing.is_empty() {
+            println!(
+                "Every keysound in {} has an audio file.",
+                bms.path().display()
+            );
+            return Ok(());
+        }
+
+        crate::print_missing_keysounds(&missing);
+
+        return Err(Failure::MissingAudio);
+    }
+
+    let ids: Vec<u64> = remap.iter().map(|(id, _)| *id).collect();
+    check_defined(&bms, &ids)?;
+
+    let resolver = FileResolver::new(bms.folder());
+
+    for (id, file) in remap {
+        if resolver.find(file).is_none() {
+            return Err(Failure::Error(format!(
+                "{} doesn't exist in {}",
+                file,
+                bms.folder().display()
+            )));
+        }
+
+        if let Some(keysound) = bms.get_keysound_mut(*id) {
+            println!("Pointing {} at {}", as_str(*id), file);
+            keysound.set_keysound_file(file.clone());
+        }
+    }
+
+    let remaining: Vec<_> = missing
+        .iter()
+        .filter(|missing| !ids.contains(&missing.keysound.keysound_id()))
+        .collect();
+
+    for missing_keysound in &remaining {
+        let id = missing_keysound.keysound.keysound_id();
+
+        if silence {
+            println!("Silencing {}", missing_keysound);
+
+            if let Some(keysound) = bms.get_keysound_mut(id) {
+                keysound.set_keysound_file(SILENT_PLACEHOLDER.to_string());
+            }
+        } else if remove {
+            println!("Removing {}", missing_keysound);
+            bms.remove_keysound(id);
+        }
+    }
+
+    let mut history = load_history(chart);
+
+    save(
+        &original,
+        &mut bms,
+        &mut history,
+        "Fix missing audio",
+        options,
+    )?;
+
+    if silence && !remaining.is_empty() && !options.dry_run {
+        let path = bms.folder().join(SILENT_PLACEHOLDER);
+
+        write_silent_placeholder(&path)?;
+        println!("Silent keysounds play {}", path.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1074 @@
+use std::{collections::BTreeMap, fmt::Display, ops::RangeInclusive, path::Path};
+
+use crate::{
+    error::{Diagnostic, ParseError},
+    line::{Line, LineEnding},
+};
+
+/// A control flow command, which decides which lines of a chart are played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlKind {
+    /// Picks a value from 1 to n each time the chart is played.
+    Random(u64),
+    /// Fixes the value of a random block, mostly used for testing a branch.
+    SetRandom(u64),
+    EndRandom,
+    If(u64),
+    ElseIf(u64),
+    Else,
+    EndIf,
+    /// Picks a value from 1 to n, like `#RANDOM`, but selects a `#CASE`.
+    Switch(u64),
+    SetSwitch(u64),
+    Case(u64),
+    /// Leaves the enclosing `#SWITCH`. Cases without one fall through to the next case.
+    Skip,
+    /// The case for values that no `#CASE` matches.
+    Def,
+    EndSwitch,
+}
+
+impl ControlKind {
+    pub fn command(&self) -> &'static str {
+        match self {
+            ControlKind::Random(_) => "RANDOM",
+            ControlKind::SetRandom(_) => "SETRANDOM",
+            ControlKind::EndRandom => "ENDRANDOM",
+            ControlKind::If(_) => "IF",
+            ControlKind::ElseIf(_) => "ELSEIF",
+            ControlKind::Else => "ELSE",
+            ControlKind::EndIf => "ENDIF",
+            ControlKind::Switch(_) => "SWITCH",
+            ControlKind::SetSwitch(_) => "SETSWITCH",
+            ControlKind::Case(_) => "CASE",
+            ControlKind::Skip => "SKIP",
+            ControlKind::Def => "DEF",
+            ControlKind::EndSwitch => "ENDSW",
+        }
+    }
+
+    /// The number after the command, for the commands that take one.
+    pub fn value(&self) -> Option<u64> {
+        match self {
+            ControlKind::Random(value)
+            | ControlKind::SetRandom(value)
+            | ControlKind::If(value)
+            | ControlKind::ElseIf(value)
+            | ControlKind::Switch(value)
+            | ControlKind::SetSwitch(value)
+            | ControlKind::Case(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The values a `#RANDOM`, `#SETRANDOM`, `#SWITCH` or `#SETSWITCH` can pick. Empty for
+    /// every other command.
+    pub fn values(&self) -> ValueSet {
+        match self {
+            ControlKind::Random(max) | ControlKind::Switch(max) => ValueSet::from_range(1..=*max),
+            ControlKind::SetRandom(value) | ControlKind::SetSwitch(value) => {
+                ValueSet::from_range(*value..=*value)
+            }
+            _ => ValueSet::new(),
+        }
+    }
+
+    /// Looks up a command by name, ignoring case. Commands that take a number are given 0.
+    fn from_command(command: &str) -> Option<Self> {
+        [
+            ControlKind::Random(0),
+            ControlKind::SetRandom(0),
+            ControlKind::EndRandom,
+            ControlKind::If(0),
+            ControlKind::ElseIf(0),
+            ControlKind::Else,
+            ControlKind::EndIf,
+            ControlKind::Switch(0),
+            ControlKind::SetSwitch(0),
+            ControlKind::Case(0),
+            ControlKind::Skip,
+            ControlKind::Def,
+            ControlKind::EndSwitch,
+        ]
+        .into_iter()
+        .find(|kind| kind.command().eq_ignore_ascii_case(command))
+    }
+
+    fn with_value(self, value: u64) -> Self {
+        match self {
+            ControlKind::Random(_) => ControlKind::Random(value),
+            ControlKind::SetRandom(_) => ControlKind::SetRandom(value),
+            ControlKind::If(_) => ControlKind::If(value),
+            ControlKind::ElseIf(_) => ControlKind::ElseIf(value),
+            ControlKind::Switch(_) => ControlKind::Switch(value),
+            ControlKind::SetSwitch(_) => ControlKind::SetSwitch(value),
+            ControlKind::Case(_) => ControlKind::Case(value),
+            kind => kind,
+        }
+    }
+}
+
+/// A control flow line such as `#RANDOM 2` or `#ENDIF`.
+#[derive(Debug, Clone)]
+pub struct Control {
+    kind: ControlKind,
+
+    /// The text the command was parsed from, dropped once it is modified.
+    source: Option<String>,
+    pub(crate) ending: LineEnding,
+}
+
+impl Control {
+    pub fn new(kind: ControlKind) -> Self {
+        Self {
+            kind,
+            source: None,
+            ending: LineEnding::default(),
+        }
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, ParseError> {
+        let (command, offset, value) = split_command(line)
+            .ok_or_else(|| ParseError::new(1, "Expected a control flow command such as #IF"))?;
+
+        let kind = ControlKind::from_command(command)
+            .ok_or_else(|| ParseError::new(2, format!("Unknown command #{}", command)))?;
+
+        let kind = match kind.value() {
+            Some(_) => {
+                let number = value.split_whitespace().next().unwrap_or_default();
+
+                kind.with_value(number.parse().map_err(|_| {
+                    ParseError::new(
+                        line[..offset].chars().count() + 1,
+                        format!("Expected a number after #{}", kind.command()),
+                    )
+                })?)
+            }
+            None => kind,
+        };
+
+        Ok(Self {
+            kind,
+            source: Some(line.to_string()),
+            ending: LineEnding::default(),
+        })
+    }
+
+    pub fn line_is_control(line: &str) -> bool {
+        split_command(line)
+            .is_some_and(|(command, _, _)| ControlKind::from_command(command).is_some())
+    }
+
+    pub fn kind(&self) -> ControlKind {
+        self.kind
+    }
+}
+
+impl Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            return write!(f, "{}", source);
+        }
+
+        match self.kind.value() {
+            Some(value) => write!(f, "#{} {}", self.kind.command(), value),
+            None => write!(f, "#{}", self.kind.command()),
+        }
+    }
+}
+
+/// Splits a line into its command, the byte offset of the value and the value itself.
+fn split_command(line: &str) -> Option<(&str, usize, &str)> {
+    let body = line.strip_prefix('#')?;
+
+    let end = body.find(char::is_whitespace).unwrap_or(body.len());
+    let rest = &body[end..];
+    let value = rest.trim_start();
+
+    Some((
+        &body[..end],
+        1 + end + rest.len() - value.len(),
+        value.trim_end(),
+    ))
+}
+
+/// The value picked by each `#RANDOM` and `#SWITCH` that was reached, by the line of the
+/// block.
+pub type Outcome = BTreeMap<usize, u64>;
+
+/// A set of values of a `#RANDOM` or `#SWITCH`, kept as ranges since a `#RANDOM` can pick
+/// from any number of values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValueSet {
+    /// Sorted, and never overlapping or touching.
+    ranges: Vec<RangeInclusive<u64>>,
+}
+
+impl ValueSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_range(range: RangeInclusive<u64>) -> Self {
+        let mut set = Self::new();
+        set.insert(range);
+        set
+    }
+
+    pub fn ranges(&self) -> &[RangeInclusive<u64>] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// How many values are in the set.
+    pub fn len(&self) -> u128 {
+        self.ranges
+            .iter()
+            .map(|range| (*range.end() - *range.start()) as u128 + 1)
+            .sum()
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.ranges.iter().any(|range| range.contains(&value))
+    }
+
+    pub fn intersects(&self, other: &ValueSet) -> bool {
+        self.ranges.iter().any(|a| {
+            other
+                .ranges
+                .iter()
+                .any(|b| a.start() <= b.end() && b.start() <= a.end())
+        })
+    }
+
+    /// The value at a position in the set, counting from the lowest value.
+    pub fn nth(&self, mut n: u128) -> Option<u64> {
+        for range in &self.ranges {
+            let len = (*range.end() - *range.start()) as u128 + 1;
+
+            if n < len {
+                return Some(*range.start() + n as u64);
+            }
+
+            n -= len;
+        }
+
+        None
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges.iter().flat_map(|range| range.clone())
+    }
+
+    pub fn insert(&mut self, range: RangeInclusive<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let (mut start, mut end) = range.into_inner();
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+
+        for range in self.ranges.drain(..) {
+            if range.end().saturating_add(1) >= start && end.saturating_add(1) >= *range.start() {
+                start = start.min(*range.start());
+                end = end.max(*range.end());
+            } else {
+                ranges.push(range);
+            }
+        }
+
+        ranges.push(start..=end);
+        ranges.sort_by_key(|range| *range.start());
+
+        self.ranges = ranges;
+    }
+
+    pub fn union(&mut self, other: &ValueSet) {
+        other
+            .ranges
+            .iter()
+            .for_each(|range| self.insert(range.clone()));
+    }
+
+    pub fn intersection(&self, other: &ValueSet) -> ValueSet {
+        let mut intersection = ValueSet::new();
+
+        for a in &self.ranges {
+            for b in &other.ranges {
+                intersection.insert(*a.start().max(b.start())..=*a.end().min(b.end()));
+            }
+        }
+
+        intersection
+    }
+
+    pub fn is_subset(&self, other: &ValueSet) -> bool {
+        self.difference(other).is_empty()
+    }
+
+    pub fn difference(&self, other: &ValueSet) -> ValueSet {
+        let mut ranges = self.ranges.clone();
+
+        for removed in &other.ranges {
+            ranges = ranges
+                .into_iter()
+                .flat_map(|range| {
+                    if range.end() < removed.start() || range.start() > removed.end() {
+                        return vec![range];
+                    }
+
+                    let mut pieces = Vec::new();
+
+                    if range.start() < removed.start() {
+                        pieces.push(*range.start()..=*removed.start() - 1);
+                    }
+
+                    if range.end() > removed.end() {
+                        pieces.push(*removed.end() + 1..=*range.end());
+                    }
+
+                    pieces
+                })
+                .collect();
+        }
+
+        ValueSet { ranges }
+    }
+
+    /// The set with only `value`, if it is in this set.
+    fn only(&self, value: u64) -> ValueSet {
+        if self.contains(value) {
+            ValueSet::from_range(value..=value)
+        } else {
+            ValueSet::new()
+        }
+    }
+}
+
+impl Display for ValueSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|range| {
+                if range.start() == range.end() {
+                    range.start().to_string()
+                } else {
+                    format!("{}-{}", range.start(), range.end())
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(f, "{}", ranges)
+    }
+}
+
+/// A piece of a chart's control flow. Lines are referred to by their index in
+/// [`BMSFile::lines`](crate::chart::BMSFile::lines).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// Any other line, including control flow commands that don't belong to a block.
+    Line(usize),
+    Random(RandomBlock),
+    If(IfBlock),
+    Switch(SwitchBlock),
+    /// A `#SKIP`, which leaves the enclosing `#SWITCH`.
+    Skip(usize),
+}
+
+/// A `#RANDOM` or `#SETRANDOM` and the lines that follow it. The lines outside of its `#IF`s
+/// are always played.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RandomBlock {
+    pub line: usize,
+    pub kind: ControlKind,
+    pub body: Vec<Node>,
+    /// The `#ENDRANDOM`. Most charts leave it out, ending the block at the next `#RANDOM` or at
+    /// the end of the enclosing branch.
+    pub end: Option<usize>,
+}
+
+/// An `#IF` with its `#ELSEIF` and `#ELSE` branches. Each compares against the value of the
+/// enclosing random block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfBlock {
+    pub branches: Vec<Branch>,
+    pub end: Option<usize>,
+}
+
+/// A `#SWITCH` or `#SETSWITCH` with its `#CASE` and `#DEF` branches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwitchBlock {
+    pub line: usize,
+    pub kind: ControlKind,
+    /// The lines before the first case, which are never played.
+    pub body: Vec<Node>,
+    pub branches: Vec<Branch>,
+    pub end: Option<usize>,
+}
+
+/// One branch of an `#IF` or `#SWITCH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Branch {
+    /// The `#IF`, `#ELSEIF`, `#ELSE`, `#CASE` or `#DEF` line.
+    pub line: usize,
+    /// The value that selects the branch, or None for `#ELSE` and `#DEF`.
+    pub condition: Option<u64>,
+    pub body: Vec<Node>,
+}
+
+/// A block that a line is nested in, and the values of the block that play the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    /// The `#RANDOM` or `#SWITCH` line of the block. None for an `#IF` outside of any random
+    /// block, which is compared against 0.
+    pub block: Option<usize>,
+    /// Empty if the line is never played.
+    pub values: ValueSet,
+}
+
+/// The control flow of a chart: which of its lines are only played for some values of a
+/// `#RANDOM` or `#SWITCH`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BranchTree {
+    nodes: Vec<Node>,
+    /// The conditions of every line, outermost first.
+    scopes: Vec<Vec<Condition>>,
+}
+
+impl BranchTree {
+    /// The top level of the chart.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// The blocks a line is nested in, outermost first. Empty if the line is always played.
+    pub fn conditions(&self, line: usize) -> &[Condition] {
+        self.scopes.get(line).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn is_conditional(&self, line: usize) -> bool {
+        !self.conditions(line).is_empty()
+    }
+
+    /// Whether any values of the blocks play the line.
+    pub fn is_reachable(&self, line: usize) -> bool {
+        self.conditions(line)
+            .iter()
+            .all(|condition| !condition.values.is_empty())
+    }
+
+    /// Whether two lines can be played in the same run of the chart, which they can't if they
+    /// need different values of the same block.
+    pub fn can_play_together(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.conditions(a), self.conditions(b));
+
+        a.iter()
+            .chain(b)
+            .all(|condition| !condition.values.is_empty())
+            && a.iter().all(|a| {
+                b.iter()
+                    .filter(|b| b.block == a.block)
+                    .all(|b| a.values.intersects(&b.values))
+            })
+    }
+
+    /// Whether `line` is played in every run that plays all of `lines`. A keysound defined on
+    /// such a line replaces any earlier definition of it in those runs.
+    pub fn always_played_with(&self, line: usize, lines: &[usize]) -> bool {
+        // The values each block can take for all of `lines` to be played
+        let mut constraints: Vec<Condition> = Vec::new();
+
+        for condition in lines.iter().flat_map(|line| self.conditions(*line)) {
+            match constraints
+                .iter_mut()
+                .find(|constraint| constraint.block == condition.block)
+            {
+                Some(constraint) => {
+                    constraint.values = constraint.values.intersection(&condition.values)
+                }
+                None => constraints.push(condition.clone()),
+            }
+        }
+
+        self.conditions(line).iter().all(|condition| {
+            constraints.iter().any(|constraint| {
+                constraint.block == condition.block
+                    && constraint.values.is_subset(&condition.values)
+            })
+        })
+    }
+
+    /// Plays the chart once, asking `pick` for the value of each block as it is reached. `pick`
+    /// is given the line of the block and the values it can take.
+    pub fn outcome_with<F: FnMut(usize, &ValueSet) -> u64>(&self, mut pick: F) -> Outcome {
+        let mut outcome = Outcome::new();
+
+        self.run(&self.nodes, None, &mut outcome, &mut pick, &mut Vec::new());
+
+        outcome
+    }
+
+    /// Picks every value the way a player would, from a seed. The same seed always gives the
+    /// same outcome.
+    pub fn outcome_from_seed(&self, seed: u64) -> Outcome {
+        let mut state = seed;
+
+        self.outcome_with(|_, values| {
+            // SplitMix64, which is enough for spreading seeds over a handful of values
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^= z >> 31;
+
+            match values.len() {
+                0 => 0,
+                len => values.nth(z as u128 % len).unwrap_or_default(),
+            }
+        })
+    }
+
+    /// Every outcome of the chart, with a block only taking part if the branches it is in are
+    /// played. Returns None if there are more than `limit` outcomes.
+    pub fn outcomes(&self, limit: usize) -> Option<Vec<Outcome>> {
+        let mut outcomes = Vec::new();
+
+        // The position of the value picked by each block reached, in the order they are reached
+        let mut choices: Vec<u128> = Vec::new();
+
+        loop {
+            let mut reached: Vec<(u128, u128)> = Vec::new();
+
+            outcomes.push(self.outcome_with(|_, values| {
+                let choice = choices.get(reached.len()).copied().unwrap_or(0);
+                reached.push((choice, values.len()));
+
+                values.nth(choice).unwrap_or_default()
+            }));
+
+            if outcomes.len() > limit {
+                return None;
+            }
+
+            // Move the last block with values left on to its next value, and start the blocks
+            // after it over, since which of them are reached may change
+            match reached.iter().rposition(|(choice, len)| choice + 1 < *len) {
+                Some(last) => {
+                    choices = reached[..=last].iter().map(|(choice, _)| *choice).collect();
+                    choices[last] += 1;
+                }
+                None => return Some(outcomes),
+            }
+        }
+    }
+
+    /// The lines played for an outcome, in order, including any control flow commands that
+    /// don't belong to a block. Blocks missing from the outcome pick their lowest value.
+    pub fn played_lines(&self, outcome: &Outcome) -> Vec<usize> {
+        let mut lines = Vec::new();
+
+        self.run(
+            &self.nodes,
+            None,
+            &mut outcome.clone(),
+            &mut |line, values| {
+                outcome
+                    .get(&line)
+                    .copied()
+                    .or_else(|| values.nth(0))
+                    .unwrap_or_default()
+            },
+            &mut lines,
+        );
+
+        lines
+    }
+
+    /// Plays `nodes`, returning true if a `#SKIP` left the enclosing `#SWITCH`.
+    fn run(
+        &self,
+        nodes: &[Node],
+        random: Option<usize>,
+        outcome: &mut Outcome,
+        pick: &mut dyn FnMut(usize, &ValueSet) -> u64,
+        lines: &mut Vec<usize>,
+    ) -> bool {
+        for node in nodes {
+            match node {
+                Node::Line(line) => lines.push(*line),
+                Node::Skip(_) => return true,
+                Node::Random(block) => {
+                    let value = pick(block.line, &block.kind.values());
+                    outcome.insert(block.line, value);
+
+                    if self.run(&block.body, Some(block.line), outcome, pick, lines) {
+                        return true;
+                    }
+                }
+                Node::If(block) => {
+                    // Outside of any random block, `#IF`s compare against 0
+                    let value = random
+                        .and_then(|random| outcome.get(&random))
+                        .copied()
+                        .unwrap_or(0);
+
+                    let branch = block
+                        .branches
+                        .iter()
+                        .find(|branch| branch.condition.is_none_or(|condition| condition == value));
+
+                    if let Some(branch) = branch
+                        && self.run(&branch.body, random, outcome, pick, lines)
+                    {
+                        return true;
+                    }
+                }
+                Node::Switch(block) => {
+                    let value = pick(block.line, &block.kind.values());
+                    outcome.insert(block.line, value);
+
+                    let start = block
+                        .branches
+                        .iter()
+                        .position(|branch| branch.condition == Some(value))
+                        .or_else(|| {
+                            block
+                                .branches
+                                .iter()
+                                .position(|branch| branch.condition.is_none())
+                        });
+
+                    // Cases fall through until a `#SKIP`
+                    if let Some(start) = start {
+                        for branch in &block.branches[start..] {
+                            if self.run(&branch.body, random, outcome, pick, lines) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    fn scope_nodes(&mut self, nodes: &[Node], random: &Condition, conditions: &mut Vec<Condition>) {
+        for node in nodes {
+            match node {
+                Node::Line(line) | Node::Skip(line) => self.scopes[*line] = conditions.clone(),
+                Node::Random(block) => {
+                    self.scope_lines([Some(block.line), block.end], conditions);
+
+                    let random = Condition {
+                        block: Some(block.line),
+                        values: block.kind.values(),
+                    };
+
+                    self.scope_nodes(&block.body, &random, conditions);
+                }
+                Node::If(block) => {
+                    self.scope_lines([block.end], conditions);
+
+                    let mut matched = ValueSet::new();
+
+                    for branch in &block.branches {
+                        self.scope_lines([Some(branch.line)], conditions);
+
+                        let values = match branch.condition {
+                            Some(value) => random.values.only(value).difference(&matched),
+                            None => random.values.difference(&matched),
+                        };
+
+                        matched.union(&values);
+
+                        conditions.push(Condition {
+                            block: random.block,
+                            values,
+                        });
+                        self.scope_nodes(&branch.body, random, conditions);
+                        conditions.pop();
+                    }
+                }
+                Node::Switch(block) => self.scope_switch(block, random, conditions),
+            }
+        }
+    }
+
+    /// Cases are entered by their own value, or by `#DEF` for values without a case, and fall
+    /// through until a `#SKIP`. A `#SKIP` nested in another block might not be reached, so it
+    /// isn't taken into account.
+    fn scope_switch(
+        &mut self,
+        block: &SwitchBlock,
+        random: &Condition,
+        conditions: &mut Vec<Condition>,
+    ) {
+        self.scope_lines([Some(block.line), block.end], conditions);
+
+        let id = Some(block.line);
+        let domain = block.kind.values();
+
+        let never = Condition {
+            block: id,
+            values: ValueSet::new(),
+        };
+
+        conditions.push(never.clone());
+        self.scope_nodes(&block.body, random, conditions);
+        conditions.pop();
+
+        let mut cases = ValueSet::new();
+
+        block
+            .branches
+            .iter()
+            .filter_map(|branch| branch.condition)
+            .for_each(|value| cases.union(&domain.only(value)));
+
+        let mut matched = ValueSet::new();
+        let mut default_taken = false;
+        let mut reaching = ValueSet::new();
+
+        for branch in &block.branches {
+            self.scope_lines([Some(branch.line)], conditions);
+
+            let entry = match branch.condition {
+                Some(value) => domain.only(value).difference(&matched),
+                None if !default_taken => domain.difference(&cases),
+                None => ValueSet::new(),
+            };
+
+            matched.union(&entry);
+            default_taken |= branch.condition.is_none();
+            reaching.union(&entry);
+
+            let skip = branch
+                .body
+                .iter()
+                .position(|node| matches!(node, Node::Skip(_)));
+
+            let (played, skipped) = match skip {
+                Some(skip) => branch.body.split_at(skip + 1),
+                None => (branch.body.as_slice(), [].as_slice()),
+            };
+
+            conditions.push(Condition {
+                block: id,
+                values: reaching.clone(),
+            });
+            self.scope_nodes(played, random, conditions);
+            conditions.pop();
+
+            conditions.push(never.clone());
+            self.scope_nodes(skipped, random, conditions);
+            conditions.pop();
+
+            if skip.is_some() {
+                reaching = ValueSet::new();
+            }
+        }
+    }
+
+    fn scope_lines<const N: usize>(&mut self, lines: [Option<usize>; N], conditions: &[Condition]) {
+        for line in lines.into_iter().flatten() {
+            self.scopes[line] = conditions.to_vec();
+        }
+    }
+}
+
+/// Builds the branch tree of a chart's lines, along with a diagnostic for each control flow
+/// command that doesn't fit in it.
+pub fn parse_branches(lines: &[Line], file: Option<&Path>) -> (BranchTree, Vec<Diagnostic>) {
+    let mut parser = Parser {
+        lines,
+        index: 0,
+        open: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let mut tree = BranchTree {
+        nodes: parser.nodes(),
+        scopes: vec![Vec::new(); lines.len()],
+    };
+
+    // Outside of any random block, `#IF`s compare against 0
+    let random = Condition {
+        block: None,
+        values: ValueSet::from_range(0..=0),
+    };
+
+    let nodes = std::mem::take(&mut tree.nodes);
+    tree.scope_nodes(&nodes, &random, &mut Vec::new());
+    tree.nodes = nodes;
+
+    let diagnostics = parser
+        .errors
+        .into_iter()
+        .map(|(index, error)| Diagnostic::new(file, index + 1, error))
+        .collect();
+
+    (tree, diagnostics)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Random,
+    If,
+    Switch,
+}
+
+struct Parser<'a> {
+    lines: &'a [Line],
+    index: usize,
+    /// The blocks around the current line, innermost last.
+    open: Vec<Context>,
+    errors: Vec<(usize, ParseError)>,
+}
+
+impl Parser<'_> {
+    fn control(&self) -> Option<ControlKind> {
+        self.lines
+            .get(self.index)
+            .and_then(|line| line.as_control())
+            .map(|control| control.kind())
+    }
+
+    /// Parses lines until one that ends an open block, which is left for that block.
+    fn nodes(&mut self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+
+        while self.index < self.lines.len() {
+            let index = self.index;
+
+            let Some(kind) = self.control() else {
+                nodes.push(Node::Line(index));
+                self.index += 1;
+                continue;
+            };
+
+            let ends = match kind {
+                // A random block ends where the next one at the same level starts
+                ControlKind::Random(_) | ControlKind::SetRandom(_) => {
+                    if self.open.last() == Some(&Context::Random) {
+                        return nodes;
+                    }
+
+                    nodes.push(Node::Random(self.random(kind)));
+                    continue;
+                }
+                ControlKind::If(_) => {
+                    nodes.push(Node::If(self.if_block()));
+                    continue;
+                }
+                ControlKind::Switch(_) | ControlKind::SetSwitch(_) => {
+                    nodes.push(Node::Switch(self.switch(kind)));
+                    continue;
+                }
+                ControlKind::Skip if self.open.contains(&Context::Switch) => {
+                    nodes.push(Node::Skip(index));
+                    self.index += 1;
+                    continue;
+                }
+                ControlKind::EndRandom => Context::Random,
+                ControlKind::ElseIf(_) | ControlKind::Else | ControlKind::EndIf => Context::If,
+                ControlKind::Case(_)
+                | ControlKind::Def
+                | ControlKind::Skip
+                | ControlKind::EndSwitch => Context::Switch,
+            };
+
+            if self.open.contains(&ends) {
+                return nodes;
+            }
+
+            self.errors.push((
+                index,
+                ParseError::new(1, format!("#{} outside of a block", kind.command())),
+            ));
+            nodes.push(Node::Line(index));
+            self.index += 1;
+        }
+
+        nodes
+    }
+
+    fn random(&mut self, kind: ControlKind) -> RandomBlock {
+        let line = self.index;
+        self.index += 1;
+
+        self.open.push(Context::Random);
+        let body = self.nodes();
+        self.open.pop();
+
+        RandomBlock {
+            line,
+            kind,
+            body,
+            end: self.end(ControlKind::EndRandom),
+        }
+    }
+
+    fn if_block(&mut self) -> IfBlock {
+        let start = self.index;
+        let mut branches = Vec::new();
+
+        self.open.push(Context::If);
+
+        // Nested `#IF`s are parsed along with the body, so only the first branch is an `#IF`
+        while let Some(kind @ (ControlKind::If(_) | ControlKind::ElseIf(_) | ControlKind::Else)) =
+            self.control()
+        {
+            let line = self.index;
+            self.index += 1;
+
+            branches.push(Branch {
+                line,
+                condition: kind.value(),
+                body: self.nodes(),
+            });
+        }
+
+        self.open.pop();
+
+        let end = self.end(ControlKind::EndIf);
+
+        if end.is_none() {
+            self.errors
+                .push((start, ParseError::new(1, "#IF without a matching #ENDIF")));
+        }
+
+        IfBlock { branches, end }
+    }
+
+    fn switch(&mut self, kind: ControlKind) -> SwitchBlock {
+        let line = self.index;
+        self.index += 1;
+
+        self.open.push(Context::Switch);
+
+        let body = self.nodes();
+        let mut branches = Vec::new();
+
+        while let Some(case @ (ControlKind::Case(_) | ControlKind::Def)) = self.control() {
+            let line = self.index;
+            self.index += 1;
+
+            branches.push(Branch {
+                line,
+                condition: case.value(),
+                body: self.nodes(),
+            });
+        }
+
+        self.open.pop();
+
+        let end = self.end(ControlKind::EndSwitch);
+
+        if end.is_none() {
+            self.errors.push((
+                line,
+                ParseError::new(1, format!("#{} without a matching #ENDSW", kind.command())),
+            ));
+        }
+
+        SwitchBlock {
+            line,
+            kind,
+            body,
+            branches,
+            end,
+        }
+    }
+
+    /// Takes the line that ends a block, if it is the next one.
+    fn end(&mut self, kind: ControlKind) -> Option<usize> {
+        if self.control() == Some(kind) {
+            self.index += 1;
+            Some(self.index - 1)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::line::parse_lines;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_branches() {
+        let text = "#WAV01 a.wav
+#RANDOM 3
+#IF 1
+#00111:01
+#ELSEIF 2
+#RANDOM 2
+#IF 2
+#00112:01
+#ENDIF
+#ELSE
+#00113:01
+#ENDIF
+#00114:01
+#SWITCH 3
+#CASE 1
+#00115:01
+#CASE 2
+#00116:01
+#SKIP
+#00117:01
+#DEF
+#00118:01
+#ENDSW
+#endif
+";
+        let (lines, _) = parse_lines(text, None);
+        let (tree, diagnostics) = parse_branches(&lines, None);
+
+        let values = |line: usize| -> Vec<String> {
+            tree.conditions(line)
+                .iter()
+                .map(|condition| condition.values.to_string())
+                .collect()
+        };
+
+        // #WAV01 and the lines of the random block outside of its #IF are always played
+        assert!(!tree.is_conditional(0));
+        assert_eq!(values(3), ["1"]);
+        assert_eq!(values(7), ["2", "2"]);
+        assert_eq!(values(10), ["3"]);
+        assert!(!tree.is_conditional(12));
+
+        // Case 1 falls through into case 2, and nothing reaches past the #SKIP
+        assert_eq!(values(15), ["1"]);
+        assert_eq!(values(17), ["1-2"]);
+        assert_eq!(values(19), [""]);
+        assert_eq!(values(21), ["3"]);
+
+        assert!(tree.can_play_together(7, 12));
+        assert!(!tree.can_play_together(3, 7));
+        assert!(tree.can_play_together(15, 17));
+        assert!(!tree.is_reachable(19));
+
+        let Node::Random(random) = &tree.nodes()[1] else {
+            panic!("Expected a random block");
+        };
+        assert_eq!(random.kind, ControlKind::Random(3));
+        assert_eq!(random.end, None);
+
+        // The stray #ENDIF is reported, and kept where it was
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 24);
+        assert_eq!(random.body.last(), Some(&Node::Line(23)));
+    }
+}
@@ -0,0 +1,256 @@
+use std::io;
+
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use encoding_rs::{Encoding, UTF_8};
+
+const UTF_8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Bytes that couldn't be decoded are kept as the characters from U+10FF80 to U+10FFFF, at the
+/// end of a private use plane that no chart would use for anything else.
+const RAW_BYTE_BASE: u32 = 0x10FF00;
+
+/// The encoding a chart was read with, so that it can be written back the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextEncoding {
+    encoding: &'static Encoding,
+    bom: bool,
+}
+
+impl TextEncoding {
+    pub fn new(encoding: &'static Encoding, bom: bool) -> Self {
+        Self { encoding, bom }
+    }
+
+    /// Guesses the encoding of a chart. Most charts are Shift-JIS, but UTF-8 and EUC-KR charts
+    /// are common enough that they need to be told apart.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(UTF_8_BOM) {
+            return Self::new(UTF_8, true);
+        }
+
+        if std::str::from_utf8(bytes).is_ok() {
+            return Self::new(UTF_8, false);
+        }
+
+        // A single broken line rules an encoding out for the whole file, so each line votes for
+        // an encoding first, and the guess is only made from the lines that make sense in the
+        // encoding most of the chart looks like
+        let mut votes: Vec<(&'static Encoding, usize)> = Vec::new();
+
+        for line in split_lines(bytes).filter(|line| !line.is_ascii()) {
+            let encoding = if std::str::from_utf8(line).is_ok() {
+                UTF_8
+            } else {
+                guess(line).encoding
+            };
+
+            match votes.iter_mut().find(|(voted, _)| *voted == encoding) {
+                Some((_, weight)) => *weight += line.len(),
+                None => votes.push((encoding, line.len())),
+            }
+        }
+
+        let Some((majority, _)) = votes.into_iter().max_by_key(|(_, weight)| *weight) else {
+            return guess(bytes);
+        };
+
+        if majority == UTF_8 {
+            return Self::new(UTF_8, false);
+        }
+
+        let majority = Self::new(majority, false);
+
+        let valid: Vec<u8> = split_lines(bytes)
+            .filter(|line| majority.decode_line(line).is_some())
+            .flatten()
+            .copied()
+            .collect();
+
+        guess(&valid)
+    }
+
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    /// Decodes `bytes`, returning the text along with the encoding it should be saved in.
+    ///
+    /// Lines that aren't valid in the detected encoding keep their ASCII characters, and every
+    /// other byte is stood in for by a character that [`TextEncoding::encode`] turns back into
+    /// that byte, so that nothing is lost when the chart is saved.
+    pub fn decode(bytes: &[u8]) -> (String, Self) {
+        let detected = Self::detect(bytes);
+
+        let text = detected
+            .decode_exact(bytes)
+            .expect("A byte order mark should only be detected if the file starts with one.");
+
+        (text, detected)
+    }
+
+    /// Decodes `bytes` line by line, keeping undecodable lines as raw bytes. Returns None if a
+    /// byte order mark was expected and is missing.
+    pub(crate) fn decode_exact(&self, bytes: &[u8]) -> Option<String> {
+        let body = if self.bom {
+            bytes.strip_prefix(UTF_8_BOM)?
+        } else {
+            bytes
+        };
+
+        Some(
+            split_lines(body)
+                .map(|line| self.decode_line(line).unwrap_or_else(|| decode_raw(line)))
+                .collect(),
+        )
+    }
+
+    /// Decodes a single line, returning None if it wouldn't encode back to the same bytes.
+    fn decode_line(&self, line: &[u8]) -> Option<String> {
+        let (text, had_errors) = self.encoding.decode_without_bom_handling(line);
+
+        if had_errors || text.contains(is_raw_byte) {
+            return None;
+        }
+
+        match self.encoding.encode(&text) {
+            (encoded, _, false) if encoded == line => Some(text.into_owned()),
+            _ => None,
+        }
+    }
+
+    /// Encodes `text`, failing if it contains characters the encoding can't represent. Bytes
+    /// kept from undecodable lines are written back as they were.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, io::Error> {
+        let mut bytes = Vec::with_capacity(text.len() + UTF_8_BOM.len());
+
+        if self.bom {
+            bytes.extend_from_slice(UTF_8_BOM);
+        }
+
+        let mut rest = text;
+
+        while !rest.is_empty() {
+            let end = rest.find(is_raw_byte).unwrap_or(rest.len());
+            let (encoded, _, had_errors) = self.encoding.encode(&rest[..end]);
+
+            if had_errors {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The text can't be represented in {}.", self.name()),
+                ));
+            }
+
+            bytes.extend_from_slice(&encoded);
+            rest = &rest[end..];
+
+            let end = rest.find(|c| !is_raw_byte(c)).unwrap_or(rest.len());
+
+            bytes.extend(
+                rest[..end]
+                    .chars()
+                    .map(|c| (c as u32 - RAW_BYTE_BASE) as u8),
+            );
+            rest = &rest[end..];
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Whether `c` stands in for a byte kept from a line that couldn't be decoded.
+pub fn is_raw_byte(c: char) -> bool {
+    (RAW_BYTE_BASE + 0x80..=RAW_BYTE_BASE + 0xFF).contains(&(c as u32))
+}
+
+/// Decodes a line byte by byte, keeping ASCII as it is and standing in for every other byte
+/// with a private use character.
+fn decode_raw(line: &[u8]) -> String {
+    line.iter()
+        .map(|&byte| match byte {
+            0x00..=0x7F => byte as char,
+            _ => char::from_u32(RAW_BYTE_BASE + byte as u32)
+                .expect("Raw bytes should map to private use characters."),
+        })
+        .collect()
+}
+
+/// Splits `bytes` into lines, keeping each line's terminator.
+fn split_lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.split_inclusive(|&byte| byte == b'\n')
+}
+
+fn guess(bytes: &[u8]) -> TextEncoding {
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+    detector.feed(bytes, true);
+
+    TextEncoding::new(detector.guess(None, Utf8Detection::Deny), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{EUC_KR, SHIFT_JIS};
+
+    use super::*;
+
+    const JAPANESE_HEADER: &str =
+        "#TITLE ホワイトアウト [ANOTHER]\n#ARTIST 田中 feat. 鈴木\n#WAV01 ピアノ_01.wav\n";
+    const KOREAN_HEADER: &str = "#TITLE 하얀 눈\n#ARTIST 김민수\n#WAV01 피아노_01.wav\n";
+
+    #[test]
+    fn test_detect_shift_jis() {
+        let bytes = SHIFT_JIS.encode(JAPANESE_HEADER).0.into_owned();
+
+        let (text, encoding) = TextEncoding::decode(&bytes);
+
+        assert_eq!(encoding.encoding(), SHIFT_JIS);
+        assert_eq!(text, JAPANESE_HEADER);
+        assert_eq!(encoding.encode(&text).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_detect_euc_kr() {
+        let bytes = EUC_KR.encode(KOREAN_HEADER).0.into_owned();
+
+        let (text, encoding) = TextEncoding::decode(&bytes);
+
+        assert_eq!(encoding.encoding(), EUC_KR);
+        assert_eq!(text, KOREAN_HEADER);
+        assert_eq!(encoding.encode(&text).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_utf8_bom_round_trip() {
+        let bytes = [UTF_8_BOM, JAPANESE_HEADER.as_bytes()].concat();
+
+        let (text, encoding) = TextEncoding::decode(&bytes);
+
+        assert_eq!(encoding, TextEncoding::new(UTF_8, true));
+        assert_eq!(text, JAPANESE_HEADER);
+        assert_eq!(encoding.encode(&text).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_invalid_bytes_are_preserved() {
+        let mut bytes = SHIFT_JIS.encode(JAPANESE_HEADER).0.into_owned();
+        bytes.extend_from_slice(b"#GENRE \x81\xFF\xFE\n");
+
+        let (text, encoding) = TextEncoding::decode(&bytes);
+
+        // Only the broken line is kept as raw bytes
+        assert_eq!(encoding.encoding(), SHIFT_JIS);
+        assert!(text.starts_with(JAPANESE_HEADER));
+        assert!(text.ends_with("#GENRE \u{10FF81}\u{10FFFF}\u{10FFFE}\n"));
+        assert_eq!(encoding.encode(&text).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_unmappable_text_is_rejected() {
+        let encoding = TextEncoding::new(SHIFT_JIS, false);
+
+        assert!(encoding.encode("#TITLE 하얀 눈").is_err());
+    }
+}
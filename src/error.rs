@@ -0,0 +1,126 @@
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Why a single line couldn't be parsed. Columns are counted in characters, starting at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new<T: ToString>(column: usize, message: T) -> Self {
+        Self {
+            column,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+/// A problem found while reading a chart, along with where it was found. Lines and columns
+/// start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(file: Option<&Path>, line: usize, error: ParseError) -> Self {
+        Self {
+            file: file.map(|file| file.to_path_buf()),
+            line,
+            column: error.column,
+            message: error.message,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum BmsError {
+    /// The chart couldn't be read or written.
+    Io { path: PathBuf, source: io::Error },
+    /// The chart was changed on disk by another program since it was loaded.
+    Conflict { path: PathBuf },
+    /// Changes made on disk by another program overlap with the unsaved edits.
+    MergeConflict { path: PathBuf, conflicts: usize },
+    /// A modifications file isn't valid JSON or doesn't match the schema.
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// An operation in a modifications file can't be applied to the chart. Operations are
+    /// counted from 1.
+    Modification { operation: usize, message: String },
+    /// A measure length that isn't positive, is too long, or is for a measure past 999.
+    MeasureLength { measure: u32, message: String },
+}
+
+impl BmsError {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        BmsError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl Display for BmsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BmsError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            BmsError::Conflict { path } => write!(
+                f,
+                "{}: the file was changed by another program since it was loaded",
+                path.display()
+            ),
+            BmsError::MergeConflict { path, conflicts } => write!(
+                f,
+                "{}: {} changes made by another program overlap with unsaved edits",
+                path.display(),
+                conflicts
+            ),
+            BmsError::Json { path, source } => write!(f, "{}: {}", path.display(), source),
+            BmsError::Modification { operation, message } => {
+                write!(f, "operation {}: {}", operation, message)
+            }
+            BmsError::MeasureLength { measure, message } => {
+                write!(f, "measure {:03}: {}", measure, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BmsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmsError::Io { source, .. } => Some(source),
+            BmsError::Json { source, .. } => Some(source),
+            BmsError::Conflict { .. }
+            | BmsError::MergeConflict { .. }
+            | BmsError::Modification { .. }
+            | BmsError::MeasureLength { .. } => None,
+        }
+    }
+}
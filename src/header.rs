@@ -0,0 +1,180 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::line::LineEnding;
+
+/// The standard header commands that can be read and edited through `BMSFile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderKind {
+    Player,
+    Genre,
+    Title,
+    Subtitle,
+    Artist,
+    Subartist,
+    Bpm,
+    PlayLevel,
+    Difficulty,
+    Rank,
+    Total,
+    StageFile,
+    Banner,
+    BackBmp,
+    Preview,
+    LnObj,
+    LnType,
+}
+
+impl HeaderKind {
+    pub const ALL: [HeaderKind; 17] = [
+        HeaderKind::Player,
+        HeaderKind::Genre,
+        HeaderKind::Title,
+        HeaderKind::Subtitle,
+        HeaderKind::Artist,
+        HeaderKind::Subartist,
+        HeaderKind::Bpm,
+        HeaderKind::PlayLevel,
+        HeaderKind::Difficulty,
+        HeaderKind::Rank,
+        HeaderKind::Total,
+        HeaderKind::StageFile,
+        HeaderKind::Banner,
+        HeaderKind::BackBmp,
+        HeaderKind::Preview,
+        HeaderKind::LnObj,
+        HeaderKind::LnType,
+    ];
+
+    pub fn command(&self) -> &'static str {
+        match self {
+            HeaderKind::Player => "PLAYER",
+            HeaderKind::Genre => "GENRE",
+            HeaderKind::Title => "TITLE",
+            HeaderKind::Subtitle => "SUBTITLE",
+            HeaderKind::Artist => "ARTIST",
+            HeaderKind::Subartist => "SUBARTIST",
+            HeaderKind::Bpm => "BPM",
+            HeaderKind::PlayLevel => "PLAYLEVEL",
+            HeaderKind::Difficulty => "DIFFICULTY",
+            HeaderKind::Rank => "RANK",
+            HeaderKind::Total => "TOTAL",
+            HeaderKind::StageFile => "STAGEFILE",
+            HeaderKind::Banner => "BANNER",
+            HeaderKind::BackBmp => "BACKBMP",
+            HeaderKind::Preview => "PREVIEW",
+            HeaderKind::LnObj => "LNOBJ",
+            HeaderKind::LnType => "LNTYPE",
+        }
+    }
+
+    /// Looks up a header by its command name, ignoring case.
+    pub fn from_command(command: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.command().eq_ignore_ascii_case(command))
+    }
+}
+
+/// A standard header line such as `#TITLE` or `#BPM`.
+#[derive(Debug, Clone)]
+pub struct Header {
+    kind: HeaderKind,
+    value: String,
+
+    /// The text the header was parsed from, dropped once it is modified.
+    source: Option<String>,
+    pub(crate) ending: LineEnding,
+}
+
+impl Header {
+    pub fn new(kind: HeaderKind, value: String) -> Self {
+        Self {
+            kind,
+            value,
+            source: None,
+            ending: LineEnding::default(),
+        }
+    }
+
+    pub fn from_line(line: &str) -> Option<Self> {
+        let line_body = line.strip_prefix('#')?;
+
+        let (command, value) = match line_body.find(char::is_whitespace) {
+            Some(pos) => (&line_body[..pos], line_body[pos..].trim()),
+            None => (line_body, ""),
+        };
+
+        Some(Self {
+            kind: HeaderKind::from_command(command)?,
+            value: value.to_string(),
+            source: Some(line.to_string()),
+            ending: LineEnding::default(),
+        })
+    }
+
+    pub fn kind(&self) -> HeaderKind {
+        self.kind
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Parses the value, returning None if it isn't valid for the requested type.
+    pub fn parse<T: FromStr>(&self) -> Option<T> {
+        self.value.parse().ok()
+    }
+
+    pub fn set_value(&mut self, value: String) {
+        if value != self.value {
+            self.value = value;
+            self.source = None;
+        }
+    }
+}
+
+impl Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            return write!(f, "{}", source);
+        }
+
+        if self.value.is_empty() {
+            write!(f, "#{}", self.kind.command())
+        } else {
+            write!(f, "#{} {}", self.kind.command(), self.value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let header = Header::from_line("#TITLE 白い雪 [ANOTHER]  ").unwrap();
+        assert_eq!(header.kind(), HeaderKind::Title);
+        assert_eq!(header.value(), "白い雪 [ANOTHER]");
+
+        let header = Header::from_line("#bpm\t174.5").unwrap();
+        assert_eq!(header.kind(), HeaderKind::Bpm);
+        assert_eq!(header.parse::<f64>(), Some(174.5));
+
+        assert!(Header::from_line("#BPM01 200").is_none());
+        assert!(Header::from_line("#WAV01 a.wav").is_none());
+        assert!(Header::from_line("TITLE no hash").is_none());
+    }
+
+    #[test]
+    fn test_header_keeps_source_until_modified() {
+        let mut header = Header::from_line("#playlevel  12").unwrap();
+        assert_eq!(header.to_string(), "#playlevel  12");
+
+        header.set_value("12".to_string());
+        assert_eq!(header.to_string(), "#playlevel  12");
+
+        header.set_value("13".to_string());
+        assert_eq!(header.to_string(), "#PLAYLEVEL 13");
+    }
+}
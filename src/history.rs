@@ -0,0 +1,609 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup::BackupStore,
+    chart::BMSFile,
+    error::BmsError,
+    quarantine::{Quarantine, QuarantinedFile, absolute},
+};
+
+/// Where journals are kept, relative to the song folder.
+pub const JOURNAL_DIR: &str = ".bmsjoin/journal";
+
+/// How many steps a journal remembers. Older steps can no longer be undone.
+pub const MAX_STEPS: usize = 100;
+
+/// One saved change to a chart, along with the files it removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    pub description: String,
+    #[serde(flatten)]
+    pub kind: StepKind,
+    pub removed_files: Vec<QuarantinedFile>,
+}
+
+/// What a step did to the chart itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StepKind {
+    /// The chart was saved. Holds its text before and after the change.
+    Edit { before: String, after: String },
+    /// Only files were removed, and the chart was left as it was.
+    RemoveFiles,
+}
+
+/// The undo history of a chart, kept in a journal next to it so that it survives between
+/// sessions. Like the quarantine manifest, the journal stores paths relative to the song folder.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    #[serde(skip)]
+    path: PathBuf,
+    /// The song folder the chart is in.
+    #[serde(skip)]
+    folder: PathBuf,
+    steps: Vec<Step>,
+    /// How many steps are currently applied. Steps past this point can be redone.
+    position: usize,
+}
+
+impl History {
+    /// An empty history for a chart, replacing its journal once a step is recorded.
+    pub fn new(chart: &Path) -> Self {
+        let file_name = chart.file_name().unwrap_or_default().to_string_lossy();
+        let parent = chart.parent().unwrap_or(Path::new(""));
+
+        let path = parent.join(JOURNAL_DIR).join(format!("{}.json", file_name));
+
+        // Absolute paths let removed files be matched with the quarantine's
+        let folder = absolute(parent).unwrap_or_else(|_| parent.to_path_buf());
+
+        Self {
+            path,
+            folder,
+            ..Default::default()
+        }
+    }
+
+    /// Loads the journal of a chart, starting an empty one if there is none yet.
+    pub fn for_chart(chart: &Path) -> Result<Self, BmsError> {
+        let Self { path, folder, .. } = Self::new(chart);
+
+        let mut history: History = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|source| BmsError::Json {
+                path: path.clone(),
+                source,
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => History::default(),
+            Err(e) => return Err(BmsError::io(&path, e)),
+        };
+
+        for step in &mut history.steps {
+            step.removed_files = std::mem::take(&mut step.removed_files)
+                .into_iter()
+                .map(|file| file.resolve(&folder))
+                .collect();
+        }
+
+        Ok(Self {
+            path,
+            folder,
+            ..history
+        })
+    }
+
+    /// Where the journal is saved.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// How many of the steps are applied. The rest can be redone.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.position > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.position < self.steps.len()
+    }
+
+    /// Records a change that was just saved, dropping any steps that could be redone.
+    pub fn record(
+        &mut self,
+        description: &str,
+        before: &BMSFile,
+        after: &BMSFile,
+    ) -> Result<(), BmsError> {
+        self.push(
+            description,
+            StepKind::Edit {
+                before: before.to_text(),
+                after: after.to_text(),
+            },
+        )
+    }
+
+    /// Records a step that only removes files, which are then added with
+    /// [`History::record_removed_file`].
+    pub fn record_removal(&mut self, description: &str) -> Result<(), BmsError> {
+        self.push(description, StepKind::RemoveFiles)
+    }
+
+    fn push(&mut self, description: &str, kind: StepKind) -> Result<(), BmsError> {
+        self.steps.truncate(self.position);
+        self.steps.push(Step {
+            description: description.to_string(),
+            kind,
+            removed_files: Vec::new(),
+        });
+
+        if self.steps.len() > MAX_STEPS {
+            self.steps.drain(..self.steps.len() - MAX_STEPS);
+        }
+
+        self.position = self.steps.len();
+
+        self.save()
+    }
+
+    /// Adds a file that was quarantined to the last recorded step.
+    pub fn record_removed_file(&mut self, file: &QuarantinedFile) -> Result<(), BmsError> {
+        if let Some(step) = self
+            .position
+            .checked_sub(1)
+            .and_then(|i| self.steps.get_mut(i))
+        {
+            step.removed_files.push(file.clone());
+        }
+
+        self.save()
+    }
+
+    /// Undoes the last applied step: the files the step removed are moved back, and the chart
+    /// is backed up and saved as it was before the step. Returns the undone step, or None if
+    /// there is nothing to undo.
+    ///
+    /// Fails with [`BmsError::Conflict`] if the chart no longer matches the step, for example
+    /// after it was edited in another program, and without changing anything if one of the
+    /// files was purged from the quarantine. If a file can't be moved back or the chart can't
+    /// be saved, the files already moved back are removed again.
+    pub fn undo(
+        &mut self,
+        bms: &mut BMSFile,
+        quarantine: &mut Quarantine,
+        backups: &BackupStore,
+    ) -> Result<Option<&Step>, BmsError> {
+        if !self.can_undo() {
+            return Ok(None);
+        }
+
+        let step = &self.steps[self.position - 1];
+
+        if let StepKind::Edit { after, .. } = &step.kind
+            && bms.to_text() != *after
+        {
+            return Err(BmsError::Conflict {
+                path: bms.path().to_path_buf(),
+            });
+        }
+
+        if let Some(purged) = step
+            .removed_files
+            .iter()
+            .find(|file| !quarantine.contains(file))
+        {
+            return Err(BmsError::io(
+                &purged.stored,
+                io::Error::from(io::ErrorKind::NotFound),
+            ));
+        }
+
+        let result = undo_step(&mut self.steps[self.position - 1], bms, quarantine, backups);
+
+        if result.is_ok() {
+            self.position -= 1;
+        }
+
+        // Files moved back and forth on the way may be stored elsewhere now, so the journal is
+        // saved even if the undo failed
+        self.save()?;
+        result?;
+
+        Ok(self.steps.get(self.position))
+    }
+
+    /// Applies the next undone step again, removing its files once more and backing up and
+    /// saving the chart as it was after the step. Returns the redone step, or None if there is
+    /// nothing to redo.
+    ///
+    /// If a file can't be removed or the chart can't be saved, the files already removed are
+    /// moved back.
+    pub fn redo(
+        &mut self,
+        bms: &mut BMSFile,
+        quarantine: &mut Quarantine,
+        backups: &BackupStore,
+    ) -> Result<Option<&Step>, BmsError> {
+        if !self.can_redo() {
+            return Ok(None);
+        }
+
+        let step = &self.steps[self.position];
+
+        if let StepKind::Edit { before, .. } = &step.kind
+            && bms.to_text() != *before
+        {
+            return Err(BmsError::Conflict {
+                path: bms.path().to_path_buf(),
+            });
+        }
+
+        let result = redo_step(&mut self.steps[self.position], bms, quarantine, backups);
+
+        if result.is_ok() {
+            self.position += 1;
+        }
+
+        self.save()?;
+        result?;
+
+        Ok(self.steps.get(self.position - 1))
+    }
+
+    fn save(&self) -> Result<(), BmsError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| BmsError::io(parent, e))?;
+        }
+
+        let journal = Self {
+            steps: self
+                .steps
+                .iter()
+                .map(|step| Step {
+                    removed_files: step
+                        .removed_files
+                        .iter()
+                        .map(|file| file.relative_to(&self.folder))
+                        .collect(),
+                    ..step.clone()
+                })
+                .collect(),
+            ..self.clone()
+        };
+
+        let json = serde_json::to_vec_pretty(&journal).map_err(|source| BmsError::Json {
+            path: self.path.clone(),
+            source,
+        })?;
+
+        fs::write(&self.path, json).map_err(|e| BmsError::io(&self.path, e))
+    }
+}
+
+/// Moves the files of a step back and saves the chart as it was before the step. If the chart
+/// can't be saved, the files go back into the quarantine.
+fn undo_step(
+    step: &mut Step,
+    bms: &mut BMSFile,
+    quarantine: &mut Quarantine,
+    backups: &BackupStore,
+) -> Result<(), BmsError> {
+    restore_files(&mut step.removed_files, quarantine)?;
+
+    if let StepKind::Edit { before, after } = &step.kind
+        && before != after
+        && let Err(e) = save_chart(bms, before, backups)
+    {
+        let _ = requarantine_files(&mut step.removed_files, quarantine);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Removes the files of a step again and saves the chart as it was after the step. If the
+/// chart can't be saved, the files are moved back.
+fn redo_step(
+    step: &mut Step,
+    bms: &mut BMSFile,
+    quarantine: &mut Quarantine,
+    backups: &BackupStore,
+) -> Result<(), BmsError> {
+    requarantine_files(&mut step.removed_files, quarantine)?;
+
+    if let StepKind::Edit { before, after } = &step.kind
+        && before != after
+        && let Err(e) = save_chart(bms, after, backups)
+    {
+        let _ = restore_files(&mut step.removed_files, quarantine);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Backs up the chart and saves it as `text`. If saving fails, the chart is left as it was.
+fn save_chart(bms: &mut BMSFile, text: &str, backups: &BackupStore) -> Result<(), BmsError> {
+    if bms.path().exists() {
+        backups.backup(bms.path())?;
+    }
+
+    let current = bms.to_text();
+    bms.set_text(text);
+
+    bms.save().inspect_err(|_| bms.set_text(&current))
+}
+
+/// Moves quarantined files back to where they were removed from, newest first. Files that are
+/// no longer quarantined are skipped. If one can't be moved back, the ones already moved are
+/// quarantined again.
+fn restore_files(
+    files: &mut [QuarantinedFile],
+    quarantine: &mut Quarantine,
+) -> Result<(), BmsError> {
+    for i in (0..files.len()).rev() {
+        if !quarantine.contains(&files[i]) {
+            continue;
+        }
+
+        if let Err(e) = quarantine.restore(&files[i]) {
+            let _ = requarantine_files(&mut files[i + 1..], quarantine);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Quarantines files that were moved back again, updating where each one is stored. Files
+/// that have since disappeared are skipped. If one can't be quarantined, the ones already
+/// quarantined are moved back.
+fn requarantine_files(
+    files: &mut [QuarantinedFile],
+    quarantine: &mut Quarantine,
+) -> Result<(), BmsError> {
+    for i in 0..files.len() {
+        if !files[i].original.exists() {
+            continue;
+        }
+
+        match quarantine.requarantine(&files[i]) {
+            Ok(file) => files[i] = file,
+            Err(e) => {
+                let _ = restore_files(&mut files[..i], quarantine);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bms::as_id, channel::ReplacePolicy, testing::TempFolder};
+
+    use super::*;
+
+    #[test]
+    fn test_undo_and_redo() {
+        let folder = TempFolder::new("history");
+
+        let chart = folder.join("chart.bms");
+        let sound = folder.join("b.wav");
+        let text = "#WAV01 a.wav\r\n#WAV02 b.wav\r\n#00111:0102\r\n";
+
+        fs::write(&chart, text).unwrap();
+        fs::write(&sound, b"RIFF").unwrap();
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        let backups = BackupStore::new(&folder);
+        let mut history = History::for_chart(&chart).unwrap();
+        let mut bms = BMSFile::from_path(&chart).unwrap();
+
+        // Merge 02 into 01, then remove its file
+        let original = bms.clone();
+        bms.merge_keysounds(
+            as_id("01").unwrap(),
+            &[as_id("02").unwrap()],
+            &ReplacePolicy::default(),
+        );
+        bms.save().unwrap();
+        history.record("Merge 02 into 01", &original, &bms).unwrap();
+
+        let removed = quarantine.quarantine(&sound, Some(&chart), &[2]).unwrap();
+        history.record_removed_file(&removed).unwrap();
+
+        let merged = fs::read_to_string(&chart).unwrap();
+        assert_eq!(merged, "#WAV01 a.wav\r\n#00111:0101\r\n");
+
+        // The journal survives between sessions
+        let mut history = History::for_chart(&chart).unwrap();
+        assert_eq!(history.steps().len(), 1);
+
+        // A file in the way of the removed one stops the undo without touching the chart
+        fs::write(&sound, b"other").unwrap();
+        assert!(history.undo(&mut bms, &mut quarantine, &backups).is_err());
+        assert_eq!(fs::read_to_string(&chart).unwrap(), merged);
+        assert_eq!(history.position(), 1);
+        assert!(quarantine.contains(&removed));
+        fs::remove_file(&sound).unwrap();
+
+        let step = history
+            .undo(&mut bms, &mut quarantine, &backups)
+            .unwrap()
+            .unwrap();
+        assert_eq!(step.description, "Merge 02 into 01");
+        assert_eq!(fs::read_to_string(&chart).unwrap(), text);
+        assert_eq!(fs::read(&sound).unwrap(), b"RIFF");
+        assert!(
+            history
+                .undo(&mut bms, &mut quarantine, &backups)
+                .unwrap()
+                .is_none()
+        );
+
+        // The merged chart was backed up before it was overwritten
+        let backup = &backups.list(&chart).unwrap()[0];
+        assert_eq!(fs::read_to_string(backup.path()).unwrap(), merged);
+
+        history
+            .redo(&mut bms, &mut quarantine, &backups)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&chart).unwrap(), merged);
+        assert!(!sound.exists());
+
+        history.undo(&mut bms, &mut quarantine, &backups).unwrap();
+        assert_eq!(fs::read(&sound).unwrap(), b"RIFF");
+
+        // A chart edited elsewhere can't be redone over
+        bms.set_title("edited");
+        assert!(matches!(
+            history.redo(&mut bms, &mut quarantine, &backups),
+            Err(BmsError::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn test_file_only_steps() {
+        let folder = TempFolder::new("history-files");
+
+        let chart = folder.join("chart.bms");
+        let sound = folder.join("unused.wav");
+
+        fs::write(&chart, "#WAV01 a.wav\n#00111:01\n").unwrap();
+        fs::write(&sound, b"RIFF").unwrap();
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        let backups = BackupStore::new(&folder);
+        let mut history = History::for_chart(&chart).unwrap();
+
+        history.record_removal("Remove unused audio").unwrap();
+        let removed = quarantine.quarantine(&sound, None, &[]).unwrap();
+        history.record_removed_file(&removed).unwrap();
+
+        let mut history = History::for_chart(&chart).unwrap();
+        assert_eq!(history.steps()[0].kind, StepKind::RemoveFiles);
+
+        // The chart isn't part of the step, so editing it doesn't stop the undo
+        fs::write(&chart, "#TITLE edited\n#WAV01 a.wav\n#00111:01\n").unwrap();
+        let mut bms = BMSFile::from_path(&chart).unwrap();
+
+        history
+            .undo(&mut bms, &mut quarantine, &backups)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read(&sound).unwrap(), b"RIFF");
+        assert_eq!(
+            fs::read_to_string(&chart).unwrap(),
+            "#TITLE edited\n#WAV01 a.wav\n#00111:01\n"
+        );
+        assert!(backups.list(&chart).unwrap().is_empty());
+
+        history
+            .redo(&mut bms, &mut quarantine, &backups)
+            .unwrap()
+            .unwrap();
+        assert!(!sound.exists());
+    }
+
+    #[test]
+    fn test_failed_undo_keeps_the_journal() {
+        let folder = TempFolder::new("history-failed");
+
+        let chart = folder.join("chart.bms");
+        let sound = folder.join("b.wav");
+        let merged = "#WAV01 a.wav\n#00111:0101\n";
+
+        fs::write(&chart, "#WAV01 a.wav\n#WAV02 b.wav\n#00111:0102\n").unwrap();
+        fs::write(&sound, b"RIFF").unwrap();
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        let backups = BackupStore::new(&folder);
+        let mut history = History::for_chart(&chart).unwrap();
+        let mut bms = BMSFile::from_path(&chart).unwrap();
+
+        let original = bms.clone();
+        bms.set_text(merged);
+        bms.save().unwrap();
+        history.record("Merge 02 into 01", &original, &bms).unwrap();
+
+        let removed = quarantine.quarantine(&sound, Some(&chart), &[2]).unwrap();
+        history.record_removed_file(&removed).unwrap();
+
+        // Another program edits the chart, so saving it fails after the file was moved back
+        fs::write(&chart, "#TITLE edited\n#WAV01 a.wav\n#00111:0101\n").unwrap();
+        assert!(matches!(
+            history.undo(&mut bms, &mut quarantine, &backups),
+            Err(BmsError::Conflict { .. })
+        ));
+        assert!(!sound.exists());
+
+        // Once the edit is gone, a new session can still undo the step
+        fs::write(&chart, merged).unwrap();
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        let mut history = History::for_chart(&chart).unwrap();
+        let mut bms = BMSFile::from_path(&chart).unwrap();
+
+        history
+            .undo(&mut bms, &mut quarantine, &backups)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read(&sound).unwrap(), b"RIFF");
+        assert_eq!(
+            fs::read_to_string(&chart).unwrap(),
+            "#WAV01 a.wav\n#WAV02 b.wav\n#00111:0102\n"
+        );
+    }
+
+    #[test]
+    fn test_journal_moves_with_the_folder() {
+        let root = TempFolder::new("history-moved");
+        let folder = root.join("song");
+        fs::create_dir(&folder).unwrap();
+
+        let chart = folder.join("chart.bms");
+        let sound = folder.join("unused.wav");
+
+        fs::write(&chart, "#WAV01 a.wav\n#00111:01\n").unwrap();
+        fs::write(&sound, b"RIFF").unwrap();
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        let mut history = History::for_chart(&chart).unwrap();
+
+        history.record_removal("Remove unused audio").unwrap();
+        let removed = quarantine.quarantine(&sound, Some(&chart), &[]).unwrap();
+        history.record_removed_file(&removed).unwrap();
+
+        let journal = fs::read_to_string(history.path()).unwrap();
+        assert!(!journal.contains(&*root.to_string_lossy()));
+
+        let moved = root.join("moved");
+        fs::rename(&folder, &moved).unwrap();
+
+        let chart = moved.join("chart.bms");
+        let mut quarantine = Quarantine::new(&moved).unwrap();
+        let backups = BackupStore::new(&moved);
+        let mut history = History::for_chart(&chart).unwrap();
+        let mut bms = BMSFile::from_path(&chart).unwrap();
+
+        history
+            .undo(&mut bms, &mut quarantine, &backups)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read(moved.join("unused.wav")).unwrap(), b"RIFF");
+    }
+}
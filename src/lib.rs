@@ -0,0 +1,57 @@
+//! Reading, editing and writing BMS charts while keeping every untouched line byte for byte.
+//!
+//! [`BMSFile`] is the entry point: load a chart with [`BMSFile::from_path`], edit its keysounds
+//! and headers, then write it back with [`BMSFile::save`].
+//!
+//! ```
+//! use std::path::Path;
+//!
+//! use bmsjoin::{BMSFile, ReplacePolicy, as_id};
+//!
+//! let chart = "#TITLE example\r\n#WAV01 kick.wav\r\n#WAV02 kick2.wav\r\n#00111:0102\r\n";
+//! let mut bms = BMSFile::from_bytes(Path::new("example.bms"), chart.as_bytes());
+//!
+//! bms.merge_keysounds(as_id("01")?, &[as_id("02")?], &ReplacePolicy::default());
+//!
+//! assert_eq!(bms.title(), Some("example"));
+//! assert_eq!(
+//!     bms.to_bytes()?,
+//!     b"#TITLE example\r\n#WAV01 kick.wav\r\n#00111:0101\r\n"
+//! );
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod backup;
+pub mod bms;
+pub mod channel;
+pub mod chart;
+pub mod control;
+pub mod encoding;
+pub mod error;
+pub mod header;
+pub mod history;
+pub mod line;
+pub mod measure;
+pub mod merge;
+pub mod modifications;
+pub mod package;
+pub mod quarantine;
+pub mod resource;
+
+#[cfg(test)]
+mod testing;
+
+pub use backup::{Backup, BackupStore};
+pub use bms::{as_id, as_keysound_id, as_str};
+pub use channel::{Channel, ReplacePolicy};
+pub use chart::{BMSFile, KeysoundUsage, MissingKeysound, UndefinedKeysound};
+pub use control::{BranchTree, Condition, Control, ControlKind, Node, Outcome, ValueSet};
+pub use error::{BmsError, Diagnostic, ParseError};
+pub use header::{Header, HeaderKind};
+pub use history::{History, Step, StepKind};
+pub use line::{Keysound, Line, Note};
+pub use measure::{MeasureLength, MeasureLengths};
+pub use modifications::{FileRename, Modifications, Operation, rename_files};
+pub use package::SongPackage;
+pub use quarantine::{Quarantine, QuarantinedFile};
+pub use resource::{FileStatus, FileUsage, Resource, ResourceKind};
@@ -1,27 +1,140 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::Path, sync::LazyLock};
 
 use regex::Regex;
 
-use crate::bms::{as_id, as_str};
+use crate::{
+    bms::{as_id, as_str},
+    channel::{Channel, ReplacePolicy},
+    control::Control,
+    error::{Diagnostic, ParseError},
+    header::Header,
+    measure::MeasureLength,
+};
+
+static NOTE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^#[A-Za-z0-9]{5}:").unwrap());
+
+/// Parses every line of a chart, along with a diagnostic for each line that looked like a note
+/// or keysound definition but couldn't be parsed. Those lines are kept as generic lines so that
+/// they are still written back unchanged.
+pub fn parse_lines(text: &str, file: Option<&Path>) -> (Vec<Line>, Vec<Diagnostic>) {
+    let mut lines = Vec::new();
+    let mut diagnostics = Vec::new();
+
+    for (i, (text, ending)) in LineEnding::split(text).into_iter().enumerate() {
+        let (mut line, error) = Line::parse(text);
+        line.set_ending(ending);
+
+        if let Some(error) = error {
+            diagnostics.push(Diagnostic::new(file, i + 1, error));
+        }
+
+        lines.push(line);
+    }
+
+    (lines, diagnostics)
+}
+
+/// The terminator that followed a line in the file it was read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    /// The last line of a file without a trailing newline.
+    None,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::None => "",
+        }
+    }
+
+    /// Splits text into lines, keeping track of how each one was terminated.
+    pub fn split(text: &str) -> Vec<(&str, LineEnding)> {
+        let mut lines = Vec::new();
+        let mut rest = text;
+
+        while !rest.is_empty() {
+            match rest.find('\n') {
+                Some(pos) => {
+                    let line = &rest[..pos];
+
+                    lines.push(match line.strip_suffix('\r') {
+                        Some(line) => (line, LineEnding::CrLf),
+                        None => (line, LineEnding::Lf),
+                    });
+
+                    rest = &rest[pos + 1..];
+                }
+                None => {
+                    lines.push((rest, LineEnding::None));
+                    rest = "";
+                }
+            }
+        }
+
+        lines
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Line {
     Generic(GenericLine),
     Note(Note),
+    Keysound(Keysound),
+    Header(Header),
+    Control(Control),
+    MeasureLength(MeasureLength),
 }
 
 impl Line {
     pub fn new(line: &str) -> Self {
-        let note_regex =
-            Regex::new(r"#[A-Za-z0-9][A-Za-z0-9][A-Za-z0-9][A-Za-z0-9][A-Za-z0-9]:").unwrap();
+        Self::parse(line).0
+    }
 
-        if note_regex.is_match(line) {
-            if let Some(new_note) = Note::new(line) {
-                return Line::Note(new_note);
-            }
+    /// Parses a line, never failing. Lines that look like a note or keysound definition but are
+    /// malformed become generic lines, and the reason is returned alongside them.
+    pub fn parse(line: &str) -> (Self, Option<ParseError>) {
+        let generic = || Self::Generic(GenericLine::new(line.to_string()));
+
+        if Keysound::line_is_keysound(line) {
+            return match Keysound::from_line(line) {
+                Ok(keysound) => (Line::Keysound(keysound), None),
+                Err(e) => (generic(), Some(e)),
+            };
+        }
+
+        if let Some(header) = Header::from_line(line) {
+            return (Line::Header(header), None);
+        }
+
+        if Control::line_is_control(line) {
+            return match Control::from_line(line) {
+                Ok(control) => (Line::Control(control), None),
+                Err(e) => (generic(), Some(e)),
+            };
         }
 
-        Self::Generic(GenericLine::new(line.to_string()))
+        // Measure lengths are decimals rather than keysound pairs
+        if MeasureLength::line_is_measure_length(line) {
+            return match MeasureLength::from_line(line) {
+                Ok(length) => (Line::MeasureLength(length), None),
+                Err(e) => (generic(), Some(e)),
+            };
+        }
+
+        if Note::line_is_note(line) {
+            return match Note::parse(line) {
+                Ok(note) => (Line::Note(note), None),
+                Err(e) => (generic(), Some(e)),
+            };
+        }
+
+        (generic(), None)
     }
 
     pub fn as_note(&self) -> Option<&Note> {
@@ -30,13 +143,67 @@ impl Line {
             _ => None,
         }
     }
+
+    pub fn as_keysound(&self) -> Option<&Keysound> {
+        match &self {
+            Self::Keysound(k) => Some(k),
+            _ => None,
+        }
+    }
+
+    pub fn as_header(&self) -> Option<&Header> {
+        match &self {
+            Self::Header(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    pub fn as_control(&self) -> Option<&Control> {
+        match &self {
+            Self::Control(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_measure_length(&self) -> Option<&MeasureLength> {
+        match &self {
+            Self::MeasureLength(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn ending(&self) -> LineEnding {
+        match self {
+            Line::Generic(generic_line) => generic_line.ending,
+            Line::Note(note) => note.ending,
+            Line::Keysound(keysound) => keysound.ending,
+            Line::Header(header) => header.ending,
+            Line::Control(control) => control.ending,
+            Line::MeasureLength(length) => length.ending,
+        }
+    }
+
+    pub fn set_ending(&mut self, ending: LineEnding) {
+        match self {
+            Line::Generic(generic_line) => generic_line.ending = ending,
+            Line::Note(note) => note.ending = ending,
+            Line::Keysound(keysound) => keysound.ending = ending,
+            Line::Header(header) => header.ending = ending,
+            Line::Control(control) => control.ending = ending,
+            Line::MeasureLength(length) => length.ending = ending,
+        }
+    }
 }
 
 impl Display for Line {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let val = match self {
-            Line::Generic(generic_line) => generic_line.line().trim_end().to_string(),
+            Line::Generic(generic_line) => generic_line.line().to_string(),
             Line::Note(note) => note.to_string(),
+            Line::Keysound(keysound) => keysound.to_string(),
+            Line::Header(header) => header.to_string(),
+            Line::Control(control) => control.to_string(),
+            Line::MeasureLength(length) => length.to_string(),
         };
 
         write!(f, "{}", val)
@@ -46,83 +213,104 @@ impl Display for Line {
 #[derive(Debug, Clone)]
 pub struct Note {
     measure: u32,
-    channel: u32,
+    channel: Channel,
     keysounds: Vec<u64>,
+
+    /// The text the note was parsed from, dropped once the note is modified.
+    source: Option<String>,
+    ending: LineEnding,
 }
 
 impl Note {
     pub fn new(line: &str) -> Option<Self> {
+        Self::parse(line).ok()
+    }
+
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
         if !Self::line_is_note(line) {
-            return None;
+            return Err(ParseError::new(1, "Expected a note in the form #mmmcc:..."));
         }
 
-        let measure = match (line[1..4]).parse::<u32>() {
-            Ok(v) => v,
-            Err(e) => {
-                // eprintln!("Error parsing measure: {}", e);
-                return None;
-            }
-        };
+        // The regex guarantees the first 7 characters are ASCII
+        let measure = line[1..4]
+            .parse::<u32>()
+            .map_err(|e| ParseError::new(2, format!("Invalid measure {}: {}", &line[1..4], e)))?;
 
-        let channel = match (line[4..6]).parse::<u32>() {
-            Ok(v) => v,
-            Err(e) => {
-                // eprintln!("Error parsing channel: {}", e);
-                return None;
-            }
-        };
+        let channel = as_id(&line[4..6])
+            .map(Channel::from_id)
+            .map_err(|e| ParseError::new(5, format!("Invalid channel {}: {}", &line[4..6], e)))?;
 
-        let mut keysounds = vec![];
+        let body: Vec<char> = line[7..].trim_end().chars().collect();
 
-        if let Some(pos) = line.find(':') {
-            // let prefix = line[..=pos]; // includes ':'
-            let body = line[pos + 1..].to_string();
+        // The body starts in the 8th column
+        let body_column = 8;
 
-            for i in (0..body.len()).step_by(2) {
-                let chunk = &body[i..i + 2];
+        if !body.len().is_multiple_of(2) {
+            return Err(ParseError::new(
+                body_column + body.len() - 1,
+                format!("Expected pairs of characters, found {}", body.len()),
+            ));
+        }
 
-                let keysound_id = match as_id(chunk) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        // eprintln!("Error parsing keysound ID: {}", e);
-                        return None;
-                    }
-                };
+        let mut keysounds = Vec::with_capacity(body.len() / 2);
 
-                keysounds.push(keysound_id);
+        for (i, pair) in body.chunks(2).enumerate() {
+            if let Some(offset) = pair.iter().position(|c| !c.is_ascii_alphanumeric()) {
+                return Err(ParseError::new(
+                    body_column + i * 2 + offset,
+                    format!("Invalid character {:?} in keysound ID", pair[offset]),
+                ));
             }
+
+            let chunk: String = pair.iter().collect();
+
+            keysounds.push(
+                as_id(&chunk).map_err(|e| ParseError::new(body_column + i * 2, e.to_string()))?,
+            );
         }
 
-        Some(Self {
+        Ok(Self {
             measure,
             channel,
             keysounds,
+            source: Some(line.to_string()),
+            ending: LineEnding::default(),
         })
     }
 
-    pub(crate) fn replace_keysounds(&mut self, old_id: u64, new_id: u64) -> Option<()> {
-        // If its not a regular p1 note, return false
-        if self.channel < as_id("10").unwrap() as u32 && self.channel % 36 != 1 {
-            eprintln!("Refusing to replace keysounds.");
+    /// Replaces every use of `old_id` with `new_id`, returning how many objects were changed.
+    /// Returns None if the policy doesn't allow replacements on this note's channel.
+    pub fn replace_keysounds(
+        &mut self,
+        old_id: u64,
+        new_id: u64,
+        policy: &ReplacePolicy,
+    ) -> Option<usize> {
+        if !policy.allows(self.channel) {
             return None;
         }
 
-        self.keysounds = self
-            .keysounds
-            .iter()
-            .map(|keysound| {
-                if *keysound == old_id {
-                    new_id
-                } else {
-                    *keysound
-                }
-            })
-            .collect();
+        let mut replaced = 0;
 
-        Some(())
+        self.keysounds.iter_mut().for_each(|keysound| {
+            if *keysound == old_id {
+                *keysound = new_id;
+                replaced += 1;
+            }
+        });
+
+        if replaced > 0 {
+            self.source = None;
+        }
+
+        Some(replaced)
+    }
+
+    pub fn measure(&self) -> u32 {
+        self.measure
     }
 
-    pub fn channel(&self) -> u32 {
+    pub fn channel(&self) -> Channel {
         self.channel
     }
 
@@ -143,15 +331,16 @@ impl Note {
     }
 
     pub fn line_is_note(line: &str) -> bool {
-        let note_regex =
-            Regex::new(r"#[A-Za-z0-9][A-Za-z0-9][A-Za-z0-9][A-Za-z0-9][A-Za-z0-9]:").unwrap();
-
-        note_regex.is_match(line)
+        NOTE_REGEX.is_match(line)
     }
 }
 
 impl Display for Note {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            return write!(f, "{}", source);
+        }
+
         let keysounds_string: String = self
             .keysounds
             .iter()
@@ -161,48 +350,102 @@ impl Display for Note {
 
         write!(
             f,
-            "#{:03}{:02}:{}",
+            "#{:03}{}:{}",
             self.measure, self.channel, keysounds_string
         )
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct GenericLine {
-    line: String,
+pub struct Keysound {
+    keysound_id: u64,
+    keysound_file: String,
+
+    /// The text the definition was parsed from, dropped once it is modified.
+    source: Option<String>,
+    ending: LineEnding,
 }
 
-impl GenericLine {
-    pub fn new(line: String) -> Self {
-        Self { line }
+impl Keysound {
+    pub fn from_line(line: &str) -> Result<Self, ParseError> {
+        if !Self::line_is_keysound(line) {
+            return Err(ParseError::new(1, "Expected a keysound in the form #WAVxx"));
+        }
+
+        let keysound_id = line
+            .get(4..6)
+            .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric()))
+            .ok_or_else(|| ParseError::new(5, "Expected a two character keysound ID"))?;
+
+        // Guaranteed to be a char boundary after the ASCII ID
+        let rest = &line[6..];
+
+        if !rest.starts_with(char::is_whitespace) {
+            return Err(ParseError::new(7, "Expected a space before the file name"));
+        }
+
+        let keysound_file = rest.trim();
+
+        if keysound_file.is_empty() {
+            return Err(ParseError::new(8, "Missing keysound file name"));
+        }
+
+        Ok(Keysound {
+            keysound_id: as_id(keysound_id).map_err(|e| ParseError::new(5, e.to_string()))?,
+            keysound_file: keysound_file.to_string(),
+            source: Some(line.to_string()),
+            ending: LineEnding::default(),
+        })
     }
 
-    pub fn get_channel(&self) -> &str {
-        (self.line.get(4..6).unwrap()) as _
+    pub fn line_is_keysound(line: &str) -> bool {
+        line.get(..4)
+            .is_some_and(|command| command.eq_ignore_ascii_case("#WAV"))
     }
 
-    /*
-    pub fn get_keysounds(&self) -> Vec<u64> {
-        let mut keysounds = vec![];
+    pub fn keysound_id(&self) -> u64 {
+        self.keysound_id
+    }
 
-        let channel = self.get_channel();
+    pub fn keysound_file(&self) -> &str {
+        &self.keysound_file
+    }
 
-        if let Some(pos) = self.line().find(':') {
-            // let prefix = &self.line()[..=pos]; // includes ':'
-            let body = &self.line()[pos + 1..];
+    pub fn set_keysound_file(&mut self, keysound_file: String) {
+        if keysound_file != self.keysound_file {
+            self.keysound_file = keysound_file;
+            self.source = None;
+        }
+    }
+}
 
-            for i in (0..body.len()).step_by(2) {
-                let chunk = &body[i..i + 2];
+impl Display for Keysound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            return write!(f, "{}", source);
+        }
 
-                keysounds.push(as_id(chunk).unwrap());
-            }
+        write!(f, "#WAV{} {}", as_str(self.keysound_id), self.keysound_file)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GenericLine {
+    line: String,
+    ending: LineEnding,
+}
+
+impl GenericLine {
+    pub fn new(line: String) -> Self {
+        Self {
+            line,
+            ending: LineEnding::default(),
         }
+    }
 
-        keysounds.sort();
-        keysounds.dedup();
-        keysounds
+    pub fn get_channel(&self) -> Option<&str> {
+        self.line.get(4..6)
     }
-    */
 
     pub fn line(&self) -> &str {
         &self.line
@@ -213,7 +456,7 @@ impl GenericLine {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len() > 0
+        self.len() == 0
     }
 
     pub fn line_mut(&mut self) -> &mut String {
@@ -260,13 +503,15 @@ mod tests {
     fn test_note_serialisation() {
         let note = Note {
             measure: 50,
-            channel: 14,
+            channel: Channel::P1Visible(4),
             keysounds: [
                 "7H", "7I", "7P", "7H", "7I", "7P", "7K", "7I", "7P", "7H", "7I", "7P", "7H", "7I",
                 "7P", "7H",
             ]
             .map(|s| as_id(s).expect("Failed to create ID from strings."))
             .to_vec(),
+            source: None,
+            ending: LineEnding::Lf,
         };
 
         assert_eq!(note.to_string(), "#05014:7H7I7P7H7I7P7K7I7P7H7I7P7H7I7P7H");
@@ -288,11 +533,16 @@ mod tests {
     fn test_replace_keysounds() {
         let mut note = Note {
             measure: 1,
-            channel: as_id("11").unwrap() as u32,
+            channel: Channel::P1Visible(1),
             keysounds: vec![18, 19, 20],
+            source: None,
+            ending: LineEnding::Lf,
         };
 
-        assert!(note.replace_keysounds(18, 19).is_some());
+        assert_eq!(
+            note.replace_keysounds(18, 19, &ReplacePolicy::default()),
+            Some(1)
+        );
 
         assert_eq!(note.keysounds, vec![19, 19, 20]);
     }
@@ -301,8 +551,158 @@ mod tests {
     fn test_replace_keysounds_2() {
         let mut note = Note::new("#05201:0000SU0000SV0000").unwrap();
 
-        note.replace_keysounds(as_id("SU").unwrap(), as_id("SV").unwrap());
+        note.replace_keysounds(
+            as_id("SU").unwrap(),
+            as_id("SV").unwrap(),
+            &ReplacePolicy::default(),
+        );
 
         assert_eq!(note.to_string(), "#05201:0000SV0000SV0000");
     }
+
+    #[test]
+    fn test_replace_keysounds_respects_policy() {
+        let policy = ReplacePolicy::default();
+
+        for line in ["#00103:0A0B", "#00104:0A0B", "#00109:0A0B", "#001D1:0A0B"] {
+            let mut note = Note::new(line).unwrap();
+
+            assert_eq!(note.replace_keysounds(10, 12, &policy), None);
+            assert_eq!(note.to_string(), line);
+        }
+
+        let mut note = Note::new("#00131:0A0B").unwrap();
+
+        let no_invisible = ReplacePolicy {
+            invisible: false,
+            ..Default::default()
+        };
+
+        assert_eq!(note.replace_keysounds(10, 12, &no_invisible), None);
+        assert_eq!(note.replace_keysounds(10, 12, &policy), Some(1));
+        assert_eq!(note.to_string(), "#00131:0C0B");
+    }
+
+    #[test]
+    fn test_alphanumeric_channels() {
+        let note = Note::new("#012D1:00ZZ").expect("Failed to parse a landmine note.");
+        assert_eq!(note.channel(), Channel::P1Mine(1));
+
+        let mut note = Note::new("#0122a:0A0B").expect("Failed to parse a P2 note.");
+        assert_eq!(note.channel(), Channel::P2Visible(10));
+
+        note.replace_keysounds(
+            as_id("0A").unwrap(),
+            as_id("0C").unwrap(),
+            &ReplacePolicy::default(),
+        );
+        assert_eq!(note.to_string(), "#0122A:0C0B");
+    }
+
+    #[test]
+    fn test_malformed_lines_are_reported() {
+        let cases = [
+            ("#00111:0A0", 10),
+            ("#00111:0Aあ1", 10),
+            ("#00111:0A-1", 10),
+            ("#0A111:0A01", 2),
+            ("#WAV", 5),
+            ("#WAVあ1 a.wav", 5),
+            ("#WAV01a.wav", 7),
+            ("#WAV01   ", 8),
+            ("#RANDOM", 8),
+            ("#IF x", 5),
+            ("#00102:0", 8),
+        ];
+
+        for (line, column) in cases {
+            let (parsed, error) = Line::parse(line);
+
+            assert!(
+                matches!(parsed, Line::Generic(_)),
+                "{} should be generic",
+                line
+            );
+            assert_eq!(error.map(|e| e.column), Some(column), "{}", line);
+            assert_eq!(parsed.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn test_parser_never_panics() {
+        let fragments = [
+            "#", "#0", "#001", "#00111", "#00111:", ":", "0", "A", "z", "あ", "é", " ", "\t",
+            "#WAV", "#wav", "01", "-", "+", "\u{FFFD}", "\r", "#TITLE", "02", "ZZ", "#IF",
+            "#random",
+        ];
+
+        // Every combination of up to three fragments
+        for a in fragments {
+            for b in fragments {
+                for c in fragments {
+                    let line = format!("{}{}{}", a, b, c);
+
+                    let (parsed, _) = Line::parse(&line);
+                    assert_eq!(parsed.to_string(), line);
+
+                    let _ = GenericLine::new(line).get_channel();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_generic_line_is_empty() {
+        assert!(GenericLine::new(String::new()).is_empty());
+        assert!(!GenericLine::new("#TITLE song".to_string()).is_empty());
+    }
+
+    #[test]
+    fn test_parse_lines_diagnostics() {
+        let (lines, diagnostics) =
+            parse_lines("#WAV01 a.wav\r\n#00111:010\r\n#00102:0.75\r\n", None);
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].as_measure_length().is_some());
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: None,
+                line: 2,
+                column: 10,
+                message: "Expected pairs of characters, found 3".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_untouched_note_keeps_source() {
+        let mut note = Note::new("#05251:00su00sv").unwrap();
+
+        let policy = ReplacePolicy::default();
+
+        note.replace_keysounds(as_id("S1").unwrap(), as_id("S2").unwrap(), &policy);
+        assert_eq!(note.to_string(), "#05251:00su00sv");
+
+        note.replace_keysounds(as_id("SU").unwrap(), as_id("SW").unwrap(), &policy);
+        assert_eq!(note.to_string(), "#05251:00SW00SV");
+    }
+
+    #[test]
+    fn test_split_line_endings() {
+        assert_eq!(
+            LineEnding::split("#TITLE a\r\n#ARTIST b\n\r\n#00111:01"),
+            vec![
+                ("#TITLE a", LineEnding::CrLf),
+                ("#ARTIST b", LineEnding::Lf),
+                ("", LineEnding::CrLf),
+                ("#00111:01", LineEnding::None),
+            ]
+        );
+
+        assert_eq!(
+            LineEnding::split("#TITLE a\n"),
+            vec![("#TITLE a", LineEnding::Lf)]
+        );
+    }
 }
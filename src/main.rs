@@ -1,263 +1,728 @@
 use std::{
-    env,
-    fmt::Display,
-    fs,
-    io::{self, BufRead, BufReader, Write},
+    io::{self, Write},
     num::ParseIntError,
-    path::PathBuf,
+    path::Path,
+    process::ExitCode,
 };
 
-pub mod bms;
-pub mod line;
+use bmsjoin::{
+    BMSFile, Backup, BackupStore, BmsError, FileStatus, History, Keysound, KeysoundUsage,
+    MissingKeysound, Quarantine, QuarantinedFile, ReplacePolicy, ResourceKind, SongPackage, Step,
+    UndefinedKeysound, as_id, as_str,
+    resource::{FileResolver, SILENT_PLACEHOLDER, write_silent_placeholder},
+};
+use clap::{CommandFactory, Parser};
 
-use line::Line;
+use crate::cli::Cli;
 
-use crate::bms::{as_id, as_str};
+mod cli;
 
-#[derive(Debug, Clone)]
-struct Keysound {
-    keysound_id: u64,
-    keysound_file: String,
+pub enum Command {
+    Replace,
+    Merge,
+    CheckUndefinedKeysounds,
+    CheckMissingAudio,
+    RemoveUnusedKeysounds,
+    RemoveUnusedFiles,
+    RestoreBackup,
+    ManageQuarantine,
+    Undo,
+    Redo,
+    Quit,
+    Unknown(char),
+    Empty,
 }
 
-impl Keysound {
-    pub fn from_line(line: &str) -> Result<Self, ParseIntError> {
-        let keysound_id = line[4..6].to_string();
-        let keysound_file = line[7..].to_string();
+fn get_next_command() -> Command {
+    println!(
+        "\nWhat would you like to do:
+        r - Replace one or more keysounds with another one
+        m - Merge multiple keysounds into a single keysound
+        u - Modify unused keysounds.
+        d - Check for undefined keysounds.
+        w - Check for keysounds with missing audio.
+        a - Remove unused audio.
+        b - Restore a backup.
+        t - Restore or purge removed audio.
+        z - Undo the last change.
+        y - Redo the last undone change.
+        q - Quit the program\n\n"
+    );
+
+    let input = get_string();
 
-        Ok(Keysound {
-            keysound_id: as_id(&keysound_id)?,
-            keysound_file,
-        })
+    if input.is_empty() {
+        return Command::Empty;
+    }
+
+    match input.chars().next().unwrap() {
+        'r' => Command::Replace,
+        'm' => Command::Merge,
+        'u' => Command::RemoveUnusedKeysounds,
+        'd' => Command::CheckUndefinedKeysounds,
+        'w' => Command::CheckMissingAudio,
+        'q' => Command::Quit,
+        'a' => Command::RemoveUnusedFiles,
+        'b' => Command::RestoreBackup,
+        't' => Command::ManageQuarantine,
+        'z' => Command::Undo,
+        'y' => Command::Redo,
+        val => Command::Unknown(val),
     }
 }
 
-impl Display for Keysound {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "#WAV{} {}", as_str(self.keysound_id), self.keysound_file)
+fn get_choice() -> bool {
+    let mut line = String::new();
+
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => line.to_lowercase().starts_with("y"),
+        Err(_) => {
+            eprintln!("{}", line);
+            false
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-struct BMSFile {
-    path: PathBuf,
+fn get_string() -> String {
+    let mut line = String::new();
 
-    head: Vec<Line>,
-    keysounds: Vec<Keysound>,
-    tail: Vec<Line>,
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => line.trim_end().to_string(),
+        Err(_) => {
+            eprintln!("{}", line);
+            "".to_string()
+        }
+    }
 }
 
-impl BMSFile {
-    pub fn from_path(path: &PathBuf) -> Result<Self, std::io::Error> {
-        let mut head = Vec::new();
-        let mut keysounds: Vec<Keysound> = Default::default();
-        let mut tail = Vec::new();
-
-        BufReader::new(fs::File::open(path).expect("Unable to open file"))
-            .lines()
-            .filter(|line| line.is_ok())
-            .map(|line| line.unwrap())
-            .for_each(|line| {
-                if line.starts_with("#WAV") {
-                    keysounds.push(Keysound::from_line(&line).expect("Can't parse line."));
-                } else if keysounds.is_empty() {
-                    head.push(Line::new(&line));
-                } else {
-                    tail.push(Line::new(&line));
-                }
-            });
+fn get_strings(separating_char: char) -> Vec<String> {
+    let line = get_string();
+
+    line.split(separating_char)
+        .map(|slice| slice.to_string())
+        .collect()
+}
 
-        Ok(BMSFile {
-            path: path.clone(),
-            head,
-            keysounds,
-            tail,
-        })
+/// Moves an audio file into the quarantine, noting the chart and the keysounds that played it,
+/// and adds it to the last step of the history so that undoing the step brings it back.
+/// Returns false if the file exists but couldn't be removed.
+fn delete_audio_file(
+    quarantine: &mut Quarantine,
+    history: &mut History,
+    bms: &BMSFile,
+    file_path: &Path,
+    keysounds: &[u64],
+) -> bool {
+    if !file_path.exists() {
+        eprintln!(
+            "Skipping deletion of file {} (doesn't exist)",
+            file_path.display()
+        );
+
+        return true;
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut strings: Vec<String> = Vec::new();
+    if !file_path.is_file() {
+        eprintln!(
+            "File {} exists, but is not a regular file.",
+            file_path.display()
+        );
 
-        for line in &self.head {
-            strings.push(line.to_string());
-        }
+        return false;
+    }
 
-        for keysound in &self.keysounds {
-            strings.push(keysound.to_string())
+    match quarantine.quarantine(file_path, Some(bms.path()), keysounds) {
+        Ok(removed) => {
+            println!(
+                "Removed {} (moved to {})",
+                file_path.display(),
+                removed.stored.display()
+            );
+
+            if let Err(e) = history.record_removed_file(&removed) {
+                eprintln!(
+                    "Warning: the removal couldn't be added to the history: {}",
+                    e
+                );
+            }
+
+            true
         }
+        Err(e) => {
+            eprintln!("Error removing {}: {}", file_path.display(), e);
 
-        for line in &self.tail {
-            strings.push(line.to_string());
+            false
         }
+    }
+}
+
+fn print_undefined_keysounds(undefined: &[UndefinedKeysound]) {
+    println!("The following keysounds are used but not defined:");
+
+    undefined
+        .iter()
+        .for_each(|undefined_keysound| println!("{}", undefined_keysound));
+}
+
+/// Lists the branches that use each keysound, if the chart has any `#RANDOM` or `#SWITCH`
+/// branches.
+fn print_branch_usage(bms: &BMSFile, usage: &[KeysoundUsage]) {
+    let in_branches: Vec<&KeysoundUsage> = usage
+        .iter()
+        .filter(|usage| usage.branches.iter().any(|branch| !branch.is_empty()))
+        .collect();
+
+    if in_branches.is_empty() {
+        return;
+    }
 
-        strings.join("\n").as_bytes().to_vec()
+    println!("The following keysounds are used in branches:");
+
+    in_branches.iter().for_each(|usage| {
+        let branches = usage
+            .branches
+            .iter()
+            .map(|branch| bms.describe_branch(branch))
+            .collect::<Vec<String>>()
+            .join("; ");
+
+        println!("{} (line {}): {}", usage.keysound, usage.line + 1, branches);
+    });
+
+    println!();
+}
+
+fn print_missing_keysounds(missing: &[MissingKeysound]) {
+    println!("The following keysounds have no audio file:");
+
+    missing
+        .iter()
+        .for_each(|missing_keysound| println!("{}", missing_keysound));
+}
+
+/// Asks what to do with each keysound whose audio is missing: point it at another file, at a
+/// silent placeholder, or remove it along with its notes. Returns whether the placeholder is
+/// needed.
+fn fix_missing_keysounds(bms: &mut BMSFile, missing: &[MissingKeysound]) -> bool {
+    let resolver = FileResolver::new(bms.folder());
+    let mut placeholder = false;
+
+    for missing_keysound in missing {
+        let id = missing_keysound.keysound.keysound_id();
+
+        print!(
+            "{}: (r)emap to another file, (s)ilence, (d)elete with its notes, or leave empty to skip: ",
+            missing_keysound
+        );
+        let _ = io::stdout().flush();
+
+        match get_string().trim() {
+            "r" => {
+                print!("Enter the file to play instead: ");
+                let _ = io::stdout().flush();
+
+                let file = get_string().trim().to_string();
+
+                if resolver.find(&file).is_none() {
+                    eprintln!("{} doesn't exist. Skipping {}.", file, as_str(id));
+                    continue;
+                }
+
+                if let Some(keysound) = bms.get_keysound_mut(id) {
+                    keysound.set_keysound_file(file);
+                }
+            }
+            "s" => {
+                if let Some(keysound) = bms.get_keysound_mut(id) {
+                    keysound.set_keysound_file(SILENT_PLACEHOLDER.to_string());
+                    placeholder = true;
+                }
+            }
+            "d" => {
+                let removed = bms.remove_keysound(id);
+                println!("Removed {} and {} objects using it.", as_str(id), removed);
+            }
+            "" => {}
+            choice => eprintln!("Unknown choice: {}. Skipping {}.", choice, as_str(id)),
+        }
     }
 
-    fn has_keysound(&self, keysound_id: u64) -> bool {
-        self.get_keysound(keysound_id).is_some()
+    placeholder
+}
+
+/// Loads the undo history of a chart. A journal that can't be read is replaced by an empty one
+/// rather than stopping the chart from being edited.
+fn load_history(chart: &Path) -> History {
+    History::for_chart(chart).unwrap_or_else(|e| {
+        eprintln!("Warning: starting a new undo history: {}", e);
+        History::new(chart)
+    })
+}
+
+/// Prints a step that was undone or redone, along with the files it brought back or removed.
+fn print_step(verb: &str, step: &Step) {
+    println!("{} \"{}\"", verb, step.description);
+
+    step.removed_files.iter().for_each(|removed| {
+        println!("    {}", removed.original.display());
+    });
+}
+
+fn reload(bms: &mut BMSFile) -> Result<(), BmsError> {
+    println!("Reloading {}", bms.path().display());
+
+    bms.reload().inspect_err(|_| {
+        eprintln!("Error reloading the BMS file. Check that it still exists.");
+    })
+}
+
+/// Asks a yes/no question, answering yes straight away if `assume_yes` is set.
+fn confirm(prompt: &str, assume_yes: bool) -> bool {
+    print!("{}", prompt);
+
+    if assume_yes {
+        println!("y");
+        return true;
     }
 
-    fn uses_keysound(&self, keysound_id: u64) -> bool {
-        // Make sure the keysound actually exists
-        if self.get_keysound(keysound_id).is_none() {
-            return false;
+    io::stdout().flush().expect("Unable to flush stdout.");
+
+    get_choice()
+}
+
+/// Backs up and saves the chart, first asking for confirmation if any notes use undefined
+/// keysounds. Returns the chart as it was on disk right before it was saved, which is what
+/// undoing the save goes back to, or None if the save was declined.
+fn save_checked(
+    bms: &mut BMSFile,
+    backups: &BackupStore,
+    assume_yes: bool,
+) -> Result<Option<BMSFile>, BmsError> {
+    let undefined = bms.get_undefined_keysounds();
+
+    if !undefined.is_empty() {
+        print_undefined_keysounds(&undefined);
+
+        if !confirm("\nWould you like to save anyway (y/n)? ", assume_yes) {
+            return Ok(None);
         }
+    }
 
-        self.tail.iter().any(|line| {
-            if let Some(note) = line.as_note() {
-                note.uses_keysound(keysound_id)
-            } else {
-                false
-            }
-        })
+    if bms.path().exists() {
+        let backup = backups.backup(bms.path())?;
+        println!("Backed up to {}", backup.path().display());
     }
 
-    fn get_keysound(&self, id: u64) -> Option<&Keysound> {
-        self.keysounds.iter().find(|ks| ks.keysound_id == id)
+    println!("Saving {}", bms.path().display());
+
+    let before = bms.as_loaded();
+
+    match bms.save() {
+        Ok(()) => Ok(Some(before)),
+        Err(BmsError::Conflict { .. }) => resolve_conflict(bms, assume_yes),
+        Err(e) => Err(e),
     }
+}
+
+/// Asks what to do when the chart was changed by another program before it could be saved.
+/// Merging is picked straight away if `assume_yes` is set, so that other edits are never
+/// silently lost. Returns the chart as the other program left it, or None if the save was
+/// cancelled.
+fn resolve_conflict(bms: &mut BMSFile, assume_yes: bool) -> Result<Option<BMSFile>, BmsError> {
+    eprintln!(
+        "{} was changed by another program since it was loaded.",
+        bms.path().display()
+    );
 
-    fn get_keysound_mut(&mut self, id: u64) -> Option<&mut Keysound> {
-        self.keysounds.iter_mut().find(|ks| ks.keysound_id == id)
+    let choice = if assume_yes {
+        "m".to_string()
+    } else {
+        print!("(m)erge both sets of changes, (o)verwrite the other changes or (c)ancel? ");
+        io::stdout().flush().expect("Unable to flush stdout.");
+
+        get_string().to_lowercase()
+    };
+
+    if choice.starts_with('m') {
+        bms.merge_external_changes()?;
+        println!("Merged the changes made by the other program.");
+
+        let before = bms.as_loaded();
+        bms.save().map(|_| Some(before))
+    } else if choice.starts_with('o') {
+        let before = BMSFile::from_path(bms.path())?;
+        bms.overwrite().map(|_| Some(before))
+    } else {
+        Ok(None)
     }
+}
+
+/// Prints what saving `bms` would change compared to `original` as a unified diff. Returns
+/// false if nothing would change.
+fn print_diff(original: &BMSFile, bms: &BMSFile) -> bool {
+    let diff = original.diff(bms);
 
-    fn keysounds(&self) -> &[Keysound] {
-        &self.keysounds
+    if diff.is_empty() {
+        println!("No changes to {}.", bms.path().display());
+        return false;
     }
 
-    fn get_unused_keysounds(&self) -> Vec<Keysound> {
-        self.keysounds
-            .iter()
-            .filter(|keysound| !self.uses_keysound(keysound.keysound_id))
-            .map(|keysound| keysound.clone())
-            .collect::<Vec<Keysound>>()
+    println!("\n{}", diff);
+
+    true
+}
+
+/// Shows the changes made to the chart and saves them if they are accepted, recording them in
+/// the history as `description`. Returns false if nothing was saved.
+fn review_and_save(
+    original: &BMSFile,
+    bms: &mut BMSFile,
+    backups: &BackupStore,
+    history: &mut History,
+    description: &str,
+) -> Result<bool, BmsError> {
+    if !print_diff(original, bms) || !confirm("Apply these changes (y/n)? ", false) {
+        return Ok(false);
     }
 
-    fn reload(&mut self) -> Result<(), std::io::Error> {
-        println!("Reloading {}", self.path.display());
+    let Some(before) = save_checked(bms, backups, false)? else {
+        return Ok(false);
+    };
 
-        match Self::from_path(&self.path) {
-            Ok(new_bms) => {
-                self.head = new_bms.head;
-                self.keysounds = new_bms.keysounds;
-                self.tail = new_bms.tail;
+    history.record(description, &before, bms)?;
 
-                Ok(())
-            }
-            Err(e) => {
-                eprintln!("Error reloading the BMS file. Check that it still exists.");
-                self.head.clear();
-                self.keysounds.clear();
-                self.tail.clear();
+    Ok(true)
+}
 
-                Err(e)
-            }
+/// The IDs of the removed keysounds that played a file.
+fn keysounds_playing(bms: &BMSFile, removed: &[Keysound], file: &Path) -> Vec<u64> {
+    removed
+        .iter()
+        .filter(|keysound| bms.keysound_path(keysound) == file)
+        .map(|keysound| keysound.keysound_id())
+        .collect()
+}
+
+/// Lists the files that only the removed keysounds used and deletes them if the user agrees.
+/// Files that another chart of the song still uses are kept. The files are added to the last
+/// step of the history.
+fn offer_to_delete(history: &mut History, bms: &BMSFile, removed: &[Keysound]) {
+    let package = match SongPackage::for_chart(bms.path()) {
+        Ok(package) => package,
+        Err(e) => {
+            eprintln!("Error details: {}", e);
+            return;
         }
+    };
+
+    print_kept_files(&package, bms, removed);
+
+    let files = package.get_orphaned_files(removed);
+
+    if files.is_empty() {
+        return;
     }
 
-    fn save(&self) -> Result<(), std::io::Error> {
-        println!("Saving {}", self.path.display());
-        fs::write(&self.path, self.to_bytes())
+    println!("\nThe following audio files are no longer used:");
+
+    files.iter().for_each(|file| println!("{}", file.display()));
+
+    if !confirm("\nWould you like to delete them (y/n)? ", false) {
+        return;
     }
+
+    let mut quarantine = match Quarantine::for_chart(bms.path()) {
+        Ok(quarantine) => quarantine,
+        Err(e) => {
+            eprintln!("Error details: {}", e);
+            return;
+        }
+    };
+
+    files.iter().for_each(|file| {
+        let keysounds = keysounds_playing(bms, removed, file);
+        delete_audio_file(&mut quarantine, history, bms, file, &keysounds);
+    });
 }
 
-pub enum Command {
-    Replace,
-    Merge,
-    RemoveUnusedKeysounds,
-    RemoveUnusedFiles,
-    Quit,
-    Unknown(char),
-    Empty,
+/// Notes the files of removed keysounds that are kept because other charts of the song still
+/// use them.
+fn print_kept_files(package: &SongPackage, bms: &BMSFile, removed: &[Keysound]) {
+    let mut files: Vec<_> = removed
+        .iter()
+        .map(|keysound| bms.keysound_path(keysound))
+        .collect();
+
+    files.sort();
+    files.dedup();
+
+    for file in files {
+        let users: Vec<String> = package
+            .charts_using(&file)
+            .iter()
+            .filter(|chart| chart.path().file_name() != bms.path().file_name())
+            .map(|chart| {
+                chart
+                    .path()
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+
+        if !users.is_empty() && !bms.keysounds().any(|ks| bms.keysound_path(ks) == file) {
+            println!("Keeping {} (used by {})", file.display(), users.join(", "));
+        }
+    }
 }
 
-fn get_next_command() -> Command {
-    println!(
-        "\nWhat would you like to do:
-        r - Replace one or more keysounds with another one
-        u - Modify unused keysounds.
-        a - Remove unused audio.
-        q - Quit the program\n\n"
-    );
+/// Offers to make the same merge in the other charts of the song that define the keysounds
+/// with the same files as `original`. Each chart is reviewed and saved on its own, with its
+/// own history.
+fn merge_in_siblings(
+    original: &BMSFile,
+    target: u64,
+    ids: &[u64],
+    backups: &BackupStore,
+    description: &str,
+) {
+    let package = match SongPackage::for_chart(original.path()) {
+        Ok(package) => package,
+        Err(e) => {
+            eprintln!("Error details: {}", e);
+            return;
+        }
+    };
 
-    /*
-    println!(
-        "\nWhat would you like to do:
-        r - Replace one or more keysounds with another one
-        m - Merge multiple keysounds into a single keysound
-        q - Quit the program\n\n"
-    );
-    */
+    let mut shared_ids = ids.to_vec();
+    shared_ids.push(target);
 
-    let input = get_string();
+    let siblings: Vec<BMSFile> = package
+        .charts_sharing_keysounds(original, &shared_ids)
+        .into_iter()
+        .cloned()
+        .collect();
 
-    if input.is_empty() {
-        return Command::Empty;
+    if siblings.is_empty() {
+        return;
     }
 
-    match input.chars().next().unwrap() {
-        'r' => Command::Replace,
-        'm' => Command::Merge,
-        'u' => Command::RemoveUnusedKeysounds,
-        'q' => Command::Quit,
-        'a' => Command::RemoveUnusedFiles,
-        val => Command::Unknown(val),
+    println!("\nThese charts define the same keysounds:");
+
+    siblings
+        .iter()
+        .for_each(|sibling| println!("{}", sibling.path().display()));
+
+    if !confirm(
+        "\nWould you like to make the same change to them (y/n)? ",
+        false,
+    ) {
+        return;
     }
-}
 
-fn get_choice() -> bool {
-    let mut line = String::new();
+    for sibling in siblings {
+        let mut changed = sibling.clone();
+        changed.merge_keysounds(target, ids, &ReplacePolicy::default());
 
-    match io::stdin().read_line(&mut line) {
-        Ok(_) => line.to_lowercase().starts_with("y"),
-        Err(_) => {
-            eprintln!("{}", line);
-            false
+        let mut history = load_history(sibling.path());
+
+        if let Err(e) = review_and_save(&sibling, &mut changed, backups, &mut history, description)
+        {
+            eprintln!("Error details: {}", e);
         }
     }
 }
 
-fn get_string() -> String {
-    let mut line = String::new();
+/// Lists the removed audio of the song folder and restores or purges the files the user
+/// picks.
+fn manage_quarantine(chart: &Path) {
+    let mut quarantine = match Quarantine::for_chart(chart) {
+        Ok(quarantine) => quarantine,
+        Err(e) => {
+            eprintln!("Error details: {}", e);
+            return;
+        }
+    };
 
-    match io::stdin().read_line(&mut line) {
-        Ok(_) => line.trim_end().to_string(),
-        Err(_) => {
-            eprintln!("{}", line);
-            "".to_string()
+    if quarantine.files().is_empty() {
+        println!("No removed audio in {}.", quarantine.dir().display());
+        return;
+    }
+
+    print_quarantined_files(quarantine.files());
+
+    print!(
+        "\nEnter r and a number to restore a file (eg. r 2), p and a number to purge one, or \
+        p all to purge every file (leave empty to cancel): "
+    );
+    io::stdout().flush().expect("Unable to flush stdout.");
+
+    let choice = get_string().to_lowercase();
+    let mut words = choice.split_whitespace();
+
+    let Some(action) = words.next() else {
+        return;
+    };
+    let target = words.next().unwrap_or_default();
+
+    let files: Vec<QuarantinedFile> = if action == "p" && target == "all" {
+        quarantine.files().to_vec()
+    } else {
+        match target
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| quarantine.files().get(number.wrapping_sub(1)))
+        {
+            Some(file) => vec![file.clone()],
+            None => {
+                eprintln!("There is no removed file numbered {}.", target);
+                return;
+            }
         }
+    };
+
+    match action {
+        "r" => files
+            .iter()
+            .for_each(|file| match quarantine.restore(file) {
+                Ok(()) => println!("Restored {}", file.original.display()),
+                Err(e) => eprintln!("Error restoring {}: {}", file.original.display(), e),
+            }),
+        "p" => {
+            if !confirm(
+                &format!("Permanently delete {} files (y/n)? ", files.len()),
+                false,
+            ) {
+                return;
+            }
+
+            files.iter().for_each(|file| match quarantine.purge(file) {
+                Ok(()) => println!("Purged {}", file.original.display()),
+                Err(e) => eprintln!("Error purging {}: {}", file.original.display(), e),
+            });
+        }
+        _ => eprintln!("Unknown choice: {}", choice.trim()),
     }
 }
 
-fn get_strings(separating_char: char) -> Vec<String> {
-    let line = get_string();
+/// Prints quarantined files numbered from 1, oldest first.
+fn print_quarantined_files(files: &[QuarantinedFile]) {
+    files.iter().enumerate().for_each(|(index, file)| {
+        let source = match (&file.chart, file.keysounds.is_empty()) {
+            (Some(chart), false) => format!(
+                "  ({} in {})",
+                file.keysounds.join(","),
+                chart.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            (Some(chart), true) => format!(
+                "  ({})",
+                chart.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            (None, _) => String::new(),
+        };
+
+        println!(
+            "{:>3}. {}  {}{}",
+            index + 1,
+            file.removed,
+            file.original.display(),
+            source
+        );
+    });
+}
 
-    line.split(separating_char)
-        .map(|slice| slice.to_string())
-        .collect()
+/// Lists the backups in the song folder and restores the one the user picks.
+fn restore_backup(backups: &BackupStore) {
+    let all_backups = match backups.list_all() {
+        Ok(all_backups) => all_backups,
+        Err(e) => {
+            eprintln!("Error details: {}", e);
+            return;
+        }
+    };
+
+    if all_backups.is_empty() {
+        println!("No backups found in {}.", backups.dir().display());
+        return;
+    }
+
+    print_backups(&all_backups);
+
+    print!("\nEnter the number of the backup to restore (leave empty to cancel): ");
+    io::stdout().flush().expect("Unable to flush stdout.");
+
+    let choice = get_string();
+
+    if choice.trim().is_empty() {
+        return;
+    }
+
+    match choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| all_backups.get(number.wrapping_sub(1)))
+    {
+        Some(backup) => match backups.restore(backup) {
+            Ok(()) => println!("Restored {}", backup.original().display()),
+            Err(e) => eprintln!("Error restoring the backup: {}", e),
+        },
+        None => eprintln!("There is no backup numbered {}.", choice.trim()),
+    }
+}
+
+/// Prints backups numbered from 1, newest first.
+fn print_backups(backups: &[Backup]) {
+    backups.iter().enumerate().for_each(|(index, backup)| {
+        println!(
+            "{:>3}. {}  {}",
+            index + 1,
+            backup.timestamp(),
+            backup.original().display()
+        );
+    });
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    if let Some(command) = cli.command {
+        return cli::run(command, cli.keep_backups);
+    }
+
+    match cli.chart {
+        Some(bms_path) => interactive(
+            &bms_path,
+            &BackupStore::for_chart(&bms_path).with_retention(cli.keep_backups),
+        ),
+        None => {
+            let _ = Cli::command().print_help();
+            ExitCode::from(2)
+        }
+    }
+}
 
-    let bms_path: PathBuf = (*args[1]).into();
-    // let replacements_path: PathBuf = (*args[2]).into();
+fn interactive(bms_path: &Path, backups: &BackupStore) -> ExitCode {
+    let mut bms = match BMSFile::from_path(bms_path) {
+        Ok(bms) => bms,
+        Err(e) => {
+            eprintln!("Unable to read bms file: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    /*
-    let modifcations: Modifications = serde_json::from_reader(BufReader::new(
-        fs::File::open(replacements_path).expect("Unabel to open novas pussy for reading"),
-    ));
-    */
+    println!(
+        "Loaded {} ({})",
+        bms.path().display(),
+        bms.encoding().name()
+    );
 
-    fs::copy(
-        &bms_path,
-        bms_path.parent().unwrap().join(format!(
-            "{}_backup.bms",
-            bms_path.file_stem().unwrap().to_str().unwrap()
-        )),
-    )
-    .expect("Unable to backup file.");
+    bms.diagnostics()
+        .iter()
+        .for_each(|diagnostic| eprintln!("Warning: {}", diagnostic));
 
-    let mut bms = BMSFile::from_path(&bms_path).expect("Unable to read bms file.");
+    let mut history = load_history(bms_path);
 
     let mut quit = false;
 
@@ -278,102 +743,121 @@ fn main() {
 
                 println!();
 
-                if let Ok(id) = as_id(&new_id_line) {
-                    // Reload after getting user input
-                    if let Err(e) = bms.reload() {
-                        eprintln!("Error details: {}", e);
-                        continue;
-                    }
-                    if !bms.has_keysound(id) {
-                        eprintln!("No keysound exists with id {}", as_str(id));
-                        continue;
-                    }
-
-                    print!("Enter the ID's which you would like replaced (eg. 0B,0C,0D,0E): ");
-                    io::stdout().flush().expect("Unable to flush stdout.");
+                match cli::parse_id(&new_id_line) {
+                    Ok(id) => {
+                        // Reload after getting user input
+                        if let Err(e) = reload(&mut bms) {
+                            eprintln!("Error details: {}", e);
+                            continue;
+                        }
+                        if !bms.has_keysound(id) {
+                            eprintln!("No keysound exists with id {}", as_str(id));
+                            continue;
+                        }
 
-                    let id_list = get_strings(',');
+                        print!("Enter the ID's which you would like replaced (eg. 0B,0C,0D,0E): ");
+                        io::stdout().flush().expect("Unable to flush stdout.");
 
-                    println!();
+                        let id_list = get_strings(',');
 
-                    let res_ids: Result<Vec<u64>, ParseIntError> =
-                        id_list.iter().map(as_id).collect();
+                        println!();
 
-                    // Recheck the id in case the user edited the file in their own editor
-                    if let Err(e) = bms.reload() {
-                        eprintln!("Error details: {}", e);
-                        continue;
-                    }
+                        let res_ids: Result<Vec<u64>, String> =
+                            id_list.iter().map(|id| cli::parse_id(id)).collect();
 
-                    if !bms.has_keysound(id) {
-                        eprintln!("No keysound exists with id {}", as_str(id));
-                        continue;
-                    }
-
-                    match res_ids {
-                        Ok(ids) => {
-                            let bad_ids: Vec<u64> = ids
-                                .iter()
-                                .filter(|old_id| !bms.has_keysound(**old_id))
-                                .copied()
-                                .collect();
-
-                            if !bad_ids.is_empty() {
-                                bad_ids.iter().for_each(|id| {
-                                    eprintln!("ID {} doesn't exist in the bms file.", as_str(*id));
-                                });
-
-                                continue;
-                            }
-
-                            // Skip invalid keysounds
-                            if ids.iter().any(|old_id| !bms.has_keysound(*old_id)) {
-                                eprintln!("Keysound error. Skipping replacement.");
-                                continue;
-                            }
+                        // Recheck the id in case the user edited the file in their own editor
+                        if let Err(e) = reload(&mut bms) {
+                            eprintln!("Error details: {}", e);
+                            continue;
+                        }
 
-                            let new_id_upper = &new_id_line.to_uppercase();
+                        if !bms.has_keysound(id) {
+                            eprintln!("No keysound exists with id {}", as_str(id));
+                            continue;
+                        }
 
-                            if let Ok(new_id) = as_id(new_id_upper) {
-                                ids.iter().for_each(|old_id| {
-                                    println!("Replacing {} with {}", as_str(*old_id), as_str(id));
+                        let original = bms.clone();
+
+                        match res_ids {
+                            Ok(ids) => {
+                                let bad_ids: Vec<u64> = ids
+                                    .iter()
+                                    .filter(|old_id| !bms.has_keysound(**old_id))
+                                    .copied()
+                                    .collect();
+
+                                if !bad_ids.is_empty() {
+                                    bad_ids.iter().for_each(|id| {
+                                        eprintln!(
+                                            "ID {} doesn't exist in the bms file.",
+                                            as_str(*id)
+                                        );
+                                    });
+
+                                    continue;
+                                }
 
-                                    // Delete the old keysound
-                                    bms.keysounds.retain(|ks| ks.keysound_id != *old_id);
+                                // Skip invalid keysounds
+                                if ids.iter().any(|old_id| !bms.has_keysound(*old_id)) {
+                                    eprintln!("Keysound error. Skipping replacement.");
+                                    continue;
+                                }
 
-                                    for line in &mut bms.tail {
-                                        if let Line::Note(note) = line {
-                                            println!(
-                                                "Replacing keysound {} with {}",
-                                                *old_id, new_id
-                                            );
+                                let summary =
+                                    bms.merge_keysounds(id, &ids, &ReplacePolicy::default());
 
-                                            note.replace_keysounds(*old_id, new_id);
-                                        }
-                                    }
+                                summary.iter().for_each(|(old_id, replaced)| {
+                                    println!(
+                                        "Replaced {} with {} ({} objects)",
+                                        as_str(*old_id),
+                                        as_str(id),
+                                        replaced
+                                    );
                                 });
 
-                                if let Err(e) = bms.save() {
-                                    eprintln!("Error details: {}", e);
+                                let description = format!(
+                                    "Replace {} with {}",
+                                    ids.iter()
+                                        .map(|id| as_str(*id))
+                                        .collect::<Vec<_>>()
+                                        .join(","),
+                                    as_str(id)
+                                );
+
+                                match review_and_save(
+                                    &original,
+                                    &mut bms,
+                                    backups,
+                                    &mut history,
+                                    &description,
+                                ) {
+                                    Ok(true) => merge_in_siblings(
+                                        &original,
+                                        id,
+                                        &ids,
+                                        backups,
+                                        &description,
+                                    ),
+                                    Ok(false) => {}
+                                    Err(e) => eprintln!("Error details: {}", e),
                                 }
-                            } else {
-                                eprintln!("Error converting id {}.", new_id_upper);
                             }
+                            Err(e) => eprintln!("{}", e),
                         }
-                        Err(e) => eprintln!("Error getting input ids: {}", e),
                     }
-                } else {
-                    eprintln!("Unable to convert line to id.");
-                    continue;
+                    Err(e) => eprintln!("{}", e),
                 }
             }
             Command::RemoveUnusedKeysounds => {
-                if let Err(e) = bms.reload() {
+                if let Err(e) = reload(&mut bms) {
                     eprintln!("Error details: {}", e);
                     continue;
                 }
 
-                let unused_keysounds = bms.get_unused_keysounds();
+                print_branch_usage(&bms, &bms.keysound_usage());
+
+                let original = bms.clone();
+                let unused_keysounds = bms.remove_unused_keysounds();
 
                 if unused_keysounds.is_empty() {
                     println!("No unused keysounds are present in the .bms file.");
@@ -387,117 +871,281 @@ fn main() {
                     .iter()
                     .for_each(|keysound| println!("{}", keysound));
 
-                print!("\nWould you like to remove them from the .bms file (y/n)? ");
+                match review_and_save(
+                    &original,
+                    &mut bms,
+                    backups,
+                    &mut history,
+                    "Remove unused keysounds",
+                ) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        eprintln!("Error details: {}", e);
+                        continue;
+                    }
+                }
+
+                offer_to_delete(&mut history, &bms, &unused_keysounds);
+            }
+            Command::Merge => {
+                if let Err(e) = reload(&mut bms) {
+                    eprintln!("Error details: {}", e);
+                    continue;
+                }
+
+                print!("Enter the ID's which you would like merged (eg. 0A,0B,0C): ");
                 io::stdout().flush().expect("Unable to flush stdout.");
 
-                if get_choice() {
-                    print!(
-                        "\nWould you like to delete the corresponding audio files for the unused keysounds? (y/n)? "
-                    );
+                let id_list = get_strings(',');
+
+                println!();
 
-                    io::stdout().flush().expect("Unable to flush stdout.");
+                let mut ids = match id_list
+                    .iter()
+                    .map(|id| as_id(id.trim()))
+                    .collect::<Result<Vec<u64>, ParseIntError>>()
+                {
+                    Ok(ids) => ids,
+                    Err(e) => {
+                        eprintln!("Error getting input ids: {}", e);
+                        continue;
+                    }
+                };
 
-                    let delete_files = get_choice();
+                ids.sort();
+                ids.dedup();
 
-                    let unused_ids: Vec<u64> = unused_keysounds
-                        .iter()
-                        .map(|keysound| keysound.keysound_id)
-                        .collect();
+                if ids.len() < 2 {
+                    eprintln!("At least two keysounds are needed for a merge.");
+                    continue;
+                }
 
-                    bms.keysounds.retain(|keysound| {
-                        let keep = !unused_ids.contains(&keysound.keysound_id);
+                let bad_ids: Vec<u64> = ids
+                    .iter()
+                    .filter(|id| !bms.has_keysound(**id))
+                    .copied()
+                    .collect();
 
-                        if !keep && delete_files {
-                            let file_path = bms
-                                .path
-                                .parent()
-                                .unwrap()
-                                .join(keysound.keysound_file.clone());
+                if !bad_ids.is_empty() {
+                    bad_ids.iter().for_each(|id| {
+                        eprintln!("ID {} doesn't exist in the bms file.", as_str(*id));
+                    });
 
-                            if file_path.exists() {
-                                if file_path.is_file() {
-                                    if let Err(e) = fs::remove_file(&file_path) {
-                                        eprintln!("Error removing {}: {}", file_path.display(), e);
+                    continue;
+                }
 
-                                        // Keep the keysound if theres an error deleting the file
-                                        return true;
-                                    }
+                print!("Enter the ID to keep (leave empty to keep the most used one): ");
+                io::stdout().flush().expect("Unable to flush stdout.");
 
-                                    println!("Removed {}", file_path.display());
-                                } else {
-                                    eprintln!(
-                                        "File {} exists, but is not a regular file.",
-                                        as_str(keysound.keysound_id)
-                                    );
+                let target_line = get_string();
 
-                                    // Keep the keysound if theres an error deleting the file
-                                    return true;
-                                }
-                            } else {
-                                eprintln!(
-                                    "Skipping deletion of file {} (doesn't exist)",
-                                    file_path.display()
-                                );
-                            }
+                println!();
+
+                let target = if target_line.trim().is_empty() {
+                    bms.most_used_keysound(&ids).unwrap()
+                } else {
+                    match as_id(target_line.trim()) {
+                        Ok(id) if ids.contains(&id) => id,
+                        Ok(id) => {
+                            eprintln!("ID {} is not one of the merged keysounds.", as_str(id));
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("Unable to convert {} to an id: {}", target_line, e);
+                            continue;
                         }
+                    }
+                };
 
-                        keep
-                    });
+                let original = bms.clone();
+
+                let merged_keysounds: Vec<Keysound> = ids
+                    .iter()
+                    .filter(|id| **id != target)
+                    .filter_map(|id| bms.get_keysound(*id))
+                    .cloned()
+                    .collect();
+
+                let summary = bms.merge_keysounds(target, &ids, &ReplacePolicy::default());
+
+                println!(
+                    "Merged {} keysounds into {}:",
+                    summary.len(),
+                    bms.get_keysound(target).unwrap()
+                );
+
+                summary.iter().for_each(|(old_id, replaced)| {
+                    println!(
+                        "    {} -> {} ({} objects)",
+                        as_str(*old_id),
+                        as_str(target),
+                        replaced
+                    );
+                });
+
+                println!(
+                    "{} now has {} objects.",
+                    as_str(target),
+                    bms.count_keysound_uses(target)
+                );
+
+                let description = format!(
+                    "Merge {} into {}",
+                    ids.iter()
+                        .filter(|id| **id != target)
+                        .map(|id| as_str(*id))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    as_str(target)
+                );
 
-                    if let Err(e) = bms.save() {
+                match review_and_save(&original, &mut bms, backups, &mut history, &description) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
                         eprintln!("Error details: {}", e);
+                        continue;
                     }
                 }
+
+                merge_in_siblings(&original, target, &ids, backups, &description);
+
+                offer_to_delete(&mut history, &bms, &merged_keysounds);
             }
-            Command::Merge => {
-                //
-                continue;
+            Command::CheckUndefinedKeysounds => {
+                if let Err(e) = reload(&mut bms) {
+                    eprintln!("Error details: {}", e);
+                    continue;
+                }
+
+                let undefined = bms.get_undefined_keysounds();
+
+                if undefined.is_empty() {
+                    println!("All keysounds used in the .bms file are defined.");
+                } else {
+                    print_undefined_keysounds(&undefined);
+                }
+            }
+            Command::CheckMissingAudio => {
+                if let Err(e) = reload(&mut bms) {
+                    eprintln!("Error details: {}", e);
+                    continue;
+                }
+
+                let missing = bms.get_missing_keysounds();
+
+                if missing.is_empty() {
+                    println!("Every keysound in the .bms file has an audio file.");
+                    continue;
+                }
+
+                print_missing_keysounds(&missing);
+
+                let original = bms.clone();
+                let placeholder = fix_missing_keysounds(&mut bms, &missing);
+
+                match review_and_save(
+                    &original,
+                    &mut bms,
+                    backups,
+                    &mut history,
+                    "Fix missing audio",
+                ) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        eprintln!("Error details: {}", e);
+                        continue;
+                    }
+                }
+
+                if placeholder {
+                    let path = bms.folder().join(SILENT_PLACEHOLDER);
+
+                    match write_silent_placeholder(&path) {
+                        Ok(()) => println!("Silent keysounds play {}", path.display()),
+                        Err(e) => eprintln!("Error creating {}: {}", path.display(), e),
+                    }
+                }
             }
             Command::Unknown(c) => eprintln!("Unknown command: {}", c),
             Command::Empty => continue,
             Command::Quit => quit = true,
-            Command::RemoveUnusedFiles => {
-                // Reload after getting user input
-                if let Err(e) = bms.reload() {
+            Command::RestoreBackup => restore_backup(backups),
+            Command::ManageQuarantine => manage_quarantine(bms_path),
+            Command::Undo => {
+                if let Err(e) = reload(&mut bms) {
                     eprintln!("Error details: {}", e);
                     continue;
                 }
 
-                let file_extensions = ["ogg", "wav"];
+                let mut quarantine = match Quarantine::for_chart(bms_path) {
+                    Ok(quarantine) => quarantine,
+                    Err(e) => {
+                        eprintln!("Error details: {}", e);
+                        continue;
+                    }
+                };
 
-                let parent_dir = bms.path.parent().unwrap();
+                match history.undo(&mut bms, &mut quarantine, backups) {
+                    Ok(Some(step)) => print_step("Undid", step),
+                    Ok(None) => println!("Nothing to undo."),
+                    Err(e) => eprintln!("Unable to undo: {}", e),
+                }
+            }
+            Command::Redo => {
+                if let Err(e) = reload(&mut bms) {
+                    eprintln!("Error details: {}", e);
+                    continue;
+                }
 
-                let keysound_names = bms
-                    .keysounds
-                    .iter()
-                    .map(|ks| ks.keysound_file.clone())
-                    .collect::<Vec<String>>();
+                let mut quarantine = match Quarantine::for_chart(bms_path) {
+                    Ok(quarantine) => quarantine,
+                    Err(e) => {
+                        eprintln!("Error details: {}", e);
+                        continue;
+                    }
+                };
 
-                let unused_files = fs::read_dir(parent_dir)
-                    .unwrap()
-                    .filter_map(|entry| {
-                        let entry = entry.ok()?;
+                match history.redo(&mut bms, &mut quarantine, backups) {
+                    Ok(Some(step)) => print_step("Redid", step),
+                    Ok(None) => println!("Nothing to redo."),
+                    Err(e) => eprintln!("Unable to redo: {}", e),
+                }
+            }
+            Command::RemoveUnusedFiles => {
+                // Reload after getting user input
+                if let Err(e) = reload(&mut bms) {
+                    eprintln!("Error details: {}", e);
+                    continue;
+                }
 
-                        let path = entry.path();
+                // Files used by any chart of the song are kept
+                let files = match SongPackage::for_chart(bms_path)
+                    .and_then(|package| package.classify_files())
+                {
+                    Ok(files) => files,
+                    Err(e) => {
+                        eprintln!("Error details: {}", e);
+                        continue;
+                    }
+                };
 
-                        let extension = path.extension().and_then(|path| path.to_str())?;
+                let (unused_audio, unused_other): (Vec<_>, Vec<_>) = files
+                    .into_iter()
+                    .filter(|usage| usage.status == FileStatus::Unreferenced)
+                    .partition(|usage| usage.kind == Some(ResourceKind::Audio));
 
-                        if file_extensions.contains(&extension) {
-                            Some(path)
-                        } else {
-                            None
-                        }
-                    })
-                    .filter(|path| {
-                        !keysound_names.contains(
-                            &path
-                                .file_name()
-                                .and_then(|val| val.to_str())
-                                .map(|val| val.to_string())
-                                .unwrap_or_default(),
-                        )
-                    })
-                    .collect::<Vec<PathBuf>>();
+                if !unused_other.is_empty() {
+                    println!(
+                        "{} unreferenced images and videos were left alone.",
+                        unused_other.len()
+                    );
+                }
+
+                let unused_files: Vec<_> =
+                    unused_audio.into_iter().map(|usage| usage.path).collect();
 
                 if unused_files.is_empty() {
                     println!("No unused files found.");
@@ -514,58 +1162,26 @@ fn main() {
                 io::stdout().flush().expect("Unable to flush stdout.");
 
                 if get_choice() {
-                    println!("Deleted the fuckers.");
+                    let mut quarantine = match Quarantine::for_chart(bms_path) {
+                        Ok(quarantine) => quarantine,
+                        Err(e) => {
+                            eprintln!("Error details: {}", e);
+                            continue;
+                        }
+                    };
 
-                    unused_files.iter().for_each(|f| {
-                        if f.is_file() {
-                            if let Err(e) = fs::remove_file(f) {
-                                eprintln!("Error removing {}: {}", f.display(), e);
-                            }
+                    if let Err(e) = history.record_removal("Remove unused audio") {
+                        eprintln!("Error details: {}", e);
+                        continue;
+                    }
 
-                            println!("Removed {}", f.display());
-                        } else {
-                            eprintln!("File {} exists, but is not a regular file.", f.display());
-                        }
+                    unused_files.iter().for_each(|f| {
+                        delete_audio_file(&mut quarantine, &mut history, &bms, f, &[]);
                     });
                 }
-
-                /*
-
-                let unused_files = files
-                    .iter()
-                    .filter(|path| match path.file_name() {
-                        None => false,
-                        Some(file_name) => {
-                            keysound_names.contains(&file_name.to_str().unwrap().into())
-                        }
-                    })
-                    .collect::<Vec<_>>();
-
-                dbg!(unused_files);
-
-                files.retain(f);
-
-                let unused_files = bms
-                    .keysounds
-                    .iter()
-                    .filter_map(|ks| {
-                        if file_extensions
-                            .iter()
-                            .any(|ext| ks.keysound_file.to_lowercase().ends_with(ext))
-                        {
-                            Some(ks.keysound_file.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>();
-
-                dbg!(&files);
-
-                dbg!(unused_files);
-
-                */
             }
         }
     }
+
+    ExitCode::SUCCESS
 }
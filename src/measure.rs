@@ -0,0 +1,239 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{
+    error::{BmsError, ParseError},
+    line::{LineEnding, Note},
+};
+
+/// The longest measure accepted, as a multiple of a 4/4 measure. Players take any positive
+/// length, but anything longer than this is almost certainly a typo.
+pub const MAX_MEASURE_LENGTH: f64 = 100.0;
+
+/// Measures are written with three digits.
+pub const MAX_MEASURE: u32 = 999;
+
+/// A measure length change on channel 02, such as `#00302:0.75` for a measure of 3/4.
+#[derive(Debug, Clone)]
+pub struct MeasureLength {
+    measure: u32,
+    length: f64,
+
+    /// The text the length was parsed from, dropped once it is modified.
+    source: Option<String>,
+    pub(crate) ending: LineEnding,
+}
+
+impl MeasureLength {
+    /// Fails if the measure can't be written with three digits or the length isn't valid.
+    pub fn new(measure: u32, length: f64) -> Result<Self, BmsError> {
+        if measure > MAX_MEASURE {
+            return Err(BmsError::MeasureLength {
+                measure,
+                message: format!("Measures only go up to {}", MAX_MEASURE),
+            });
+        }
+
+        check_length(length).map_err(|message| BmsError::MeasureLength { measure, message })?;
+
+        Ok(Self {
+            measure,
+            length,
+            source: None,
+            ending: LineEnding::default(),
+        })
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, ParseError> {
+        if !Self::line_is_measure_length(line) {
+            return Err(ParseError::new(
+                1,
+                "Expected a measure length in the form #mmm02:...",
+            ));
+        }
+
+        // The note regex guarantees the first 7 characters are ASCII
+        let measure = line[1..4]
+            .parse::<u32>()
+            .map_err(|e| ParseError::new(2, format!("Invalid measure {}: {}", &line[1..4], e)))?;
+
+        // The value starts in the 8th column
+        let value = line[7..].trim();
+
+        let length = value.parse::<f64>().map_err(|_| {
+            ParseError::new(
+                8,
+                format!("Expected a decimal measure length, found {:?}", value),
+            )
+        })?;
+
+        check_length(length).map_err(|message| ParseError::new(8, message))?;
+
+        Ok(Self {
+            measure,
+            length,
+            source: Some(line.to_string()),
+            ending: LineEnding::default(),
+        })
+    }
+
+    pub fn line_is_measure_length(line: &str) -> bool {
+        Note::line_is_note(line) && line[4..6] == *"02"
+    }
+
+    pub fn measure(&self) -> u32 {
+        self.measure
+    }
+
+    /// The length as a multiple of a 4/4 measure.
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    pub fn set_length(&mut self, length: f64) -> Result<(), BmsError> {
+        check_length(length).map_err(|message| BmsError::MeasureLength {
+            measure: self.measure,
+            message,
+        })?;
+
+        if length != self.length {
+            self.length = length;
+            self.source = None;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for MeasureLength {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            return write!(f, "{}", source);
+        }
+
+        write!(f, "#{:03}02:{}", self.measure, self.length)
+    }
+}
+
+/// The length of every measure of a chart. Measures without a length change are 4/4, with a
+/// length of 1.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeasureLengths {
+    lengths: BTreeMap<u32, f64>,
+}
+
+impl MeasureLengths {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The length of a measure as a multiple of a 4/4 measure.
+    pub fn get(&self, measure: u32) -> f64 {
+        self.lengths.get(&measure).copied().unwrap_or(1.0)
+    }
+
+    /// Sets the length of a measure, replacing any length it had.
+    pub fn insert(&mut self, measure: u32, length: f64) {
+        self.lengths.insert(measure, length);
+    }
+
+    /// The measures whose length was changed, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, f64)> {
+        self.lengths
+            .iter()
+            .map(|(measure, length)| (*measure, *length))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    /// The beat a measure starts on, counting from 0 at the start of measure 000. A 4/4 measure
+    /// is 4 beats long.
+    pub fn measure_start(&self, measure: u32) -> f64 {
+        let changes: f64 = self
+            .lengths
+            .range(..measure)
+            .map(|(_, length)| length - 1.0)
+            .sum();
+
+        (measure as f64 + changes) * 4.0
+    }
+
+    /// The beat of an object `position` of the way through a measure, with 0 at its start and
+    /// 1 at its end.
+    pub fn beat(&self, measure: u32, position: f64) -> f64 {
+        self.measure_start(measure) + position * self.get(measure) * 4.0
+    }
+}
+
+impl FromIterator<(u32, f64)> for MeasureLengths {
+    /// Later lengths for the same measure replace earlier ones, as they do in players.
+    fn from_iter<T: IntoIterator<Item = (u32, f64)>>(iter: T) -> Self {
+        Self {
+            lengths: iter.into_iter().collect(),
+        }
+    }
+}
+
+fn check_length(length: f64) -> Result<(), String> {
+    if !length.is_finite() || length <= 0.0 {
+        return Err(format!(
+            "Measure lengths must be positive, found {}",
+            length
+        ));
+    }
+
+    if length > MAX_MEASURE_LENGTH {
+        return Err(format!(
+            "Measure lengths can't be over {}, found {}",
+            MAX_MEASURE_LENGTH, length
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure_lengths() {
+        let three_four = MeasureLength::from_line("#00202:0.75").unwrap();
+        assert_eq!(three_four.measure(), 2);
+        assert_eq!(three_four.length(), 0.75);
+
+        for (line, message) in [
+            ("#00202:", "Expected a decimal measure length"),
+            ("#00202:3/4", "Expected a decimal measure length"),
+            ("#00202:0", "must be positive"),
+            ("#00202:-1", "must be positive"),
+            ("#00202:NaN", "must be positive"),
+            ("#00202:1000", "can't be over"),
+        ] {
+            let error = MeasureLength::from_line(line).unwrap_err();
+            assert_eq!(error.column, 8, "{}", line);
+            assert!(error.message.contains(message), "{}", line);
+        }
+
+        assert!(MeasureLength::new(1000, 1.0).is_err());
+
+        let mut length = MeasureLength::from_line("#00202:0.750").unwrap();
+        length.set_length(0.75).unwrap();
+        assert_eq!(length.to_string(), "#00202:0.750");
+        assert!(length.set_length(0.0).is_err());
+        length.set_length(1.5).unwrap();
+        assert_eq!(length.to_string(), "#00202:1.5");
+
+        // The later length for a measure wins
+        let lengths: MeasureLengths = [(1, 2.0), (1, 0.75), (3, 1.5)].into_iter().collect();
+        assert_eq!(lengths.get(0), 1.0);
+        assert_eq!(lengths.get(1), 0.75);
+        assert_eq!(lengths.iter().collect::<Vec<_>>(), [(1, 0.75), (3, 1.5)]);
+
+        // 4 + 3 + 4 beats before measure 3, then 6 for measure 3 itself
+        assert_eq!(lengths.measure_start(3), 11.0);
+        assert_eq!(lengths.measure_start(5), 21.0);
+        assert_eq!(lengths.beat(1, 0.5), 5.5);
+    }
+}
@@ -0,0 +1,154 @@
+use similar::{Algorithm, DiffOp, capture_diff_slices};
+
+/// A run of base lines replaced by one side of the merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+}
+
+/// Merges two edited copies of `base` line by line, keeping the changes made on both sides.
+///
+/// Changes that touch the same or adjacent lines can't be merged unless both sides made the
+/// same change. In that case the number of conflicting regions is returned instead, since
+/// conflict markers would only break the chart.
+pub fn merge_lines(base: &str, ours: &str, theirs: &str) -> Result<String, usize> {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+
+    let mut hunks: Vec<(bool, Hunk)> = Vec::new();
+    hunks.extend(changes(&base_lines, ours).into_iter().map(|h| (true, h)));
+    hunks.extend(changes(&base_lines, theirs).into_iter().map(|h| (false, h)));
+    hunks.sort_by_key(|(_, hunk)| (hunk.start, hunk.end));
+
+    let mut merged = String::new();
+    let mut position = 0;
+    let mut conflicts = 0;
+    let mut index = 0;
+
+    while index < hunks.len() {
+        // Group every hunk that overlaps or touches the first one
+        let mut group_end = hunks[index].1.end;
+        let mut next = index + 1;
+
+        while next < hunks.len() && hunks[next].1.start <= group_end {
+            group_end = group_end.max(hunks[next].1.end);
+            next += 1;
+        }
+
+        let group = &hunks[index..next];
+        let from_ours = group.iter().any(|(ours, _)| *ours);
+        let from_theirs = group.iter().any(|(ours, _)| !*ours);
+
+        let same_change = group.len() == 2 && group[0].1 == group[1].1;
+
+        if from_ours && from_theirs && !same_change {
+            conflicts += 1;
+        } else {
+            for (_, hunk) in group.iter().take(if same_change { 1 } else { group.len() }) {
+                base_lines[position..hunk.start]
+                    .iter()
+                    .for_each(|line| merged.push_str(line));
+                hunk.lines.iter().for_each(|line| merged.push_str(line));
+                position = hunk.end;
+            }
+        }
+
+        index = next;
+    }
+
+    if conflicts > 0 {
+        return Err(conflicts);
+    }
+
+    base_lines[position..]
+        .iter()
+        .for_each(|line| merged.push_str(line));
+
+    Ok(merged)
+}
+
+/// The hunks that turn `base` into `other`, with touching hunks joined together.
+fn changes<'a>(base: &[&str], other: &'a str) -> Vec<Hunk<'a>> {
+    let other_lines: Vec<&str> = other.split_inclusive('\n').collect();
+
+    let mut hunks: Vec<Hunk> = Vec::new();
+
+    for op in capture_diff_slices(Algorithm::Myers, base, &other_lines) {
+        let (start, end, new_range) = match op {
+            DiffOp::Equal { .. } => continue,
+            DiffOp::Delete {
+                old_index,
+                old_len,
+                new_index,
+            } => (old_index, old_index + old_len, new_index..new_index),
+            DiffOp::Insert {
+                old_index,
+                new_index,
+                new_len,
+            } => (old_index, old_index, new_index..new_index + new_len),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => (
+                old_index,
+                old_index + old_len,
+                new_index..new_index + new_len,
+            ),
+        };
+
+        let lines = &other_lines[new_range];
+
+        match hunks.last_mut() {
+            Some(last) if last.end == start => {
+                last.end = end;
+                last.lines.extend_from_slice(lines);
+            }
+            _ => hunks.push(Hunk {
+                start,
+                end,
+                lines: lines.to_vec(),
+            }),
+        }
+    }
+
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "#TITLE a\r\n#WAV01 a.wav\r\n#WAV02 b.wav\r\n\r\n#00111:0102\r\n#00211:01";
+
+    #[test]
+    fn test_merge_separate_changes() {
+        let ours = "#TITLE a\r\n#WAV01 a.wav\r\n\r\n#00111:0101\r\n#00211:01";
+        let theirs = "#TITLE b\r\n#WAV01 a.wav\r\n#WAV02 b.wav\r\n\r\n#00111:0102\r\n#00211:01";
+
+        assert_eq!(
+            merge_lines(BASE, ours, theirs),
+            Ok("#TITLE b\r\n#WAV01 a.wav\r\n\r\n#00111:0101\r\n#00211:01".to_string())
+        );
+
+        assert_eq!(merge_lines(BASE, ours, BASE), Ok(ours.to_string()));
+        assert_eq!(merge_lines(BASE, BASE, theirs), Ok(theirs.to_string()));
+        assert_eq!(merge_lines(BASE, ours, ours), Ok(ours.to_string()));
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let ours = "#TITLE a\r\n#WAV01 a.wav\r\n\r\n#00111:0101\r\n#00211:01";
+        let theirs = "#TITLE a\r\n#WAV01 a.wav\r\n#WAV02 c.wav\r\n\r\n#00111:0102\r\n#00211:01";
+
+        assert_eq!(merge_lines(BASE, ours, theirs), Err(1));
+
+        // Touching changes are conflicts too, as the order of the new lines is unclear
+        let ours = "#TITLE a\r\n#WAV01 a.wav\r\n#WAV03 c.wav\r\n#WAV02 b.wav\r\n\r\n#00111:0102\r\n#00211:01";
+        let theirs = "#TITLE a\r\n#WAV01 a.wav\r\n#WAV04 d.wav\r\n#WAV02 b.wav\r\n\r\n#00111:0102\r\n#00211:01";
+
+        assert_eq!(merge_lines(BASE, ours, theirs), Err(1));
+    }
+}
@@ -0,0 +1,502 @@
+//! Batches of keysound and header edits read from JSON, so that a cleanup decided in review can
+//! be replayed exactly.
+//!
+//! ```json
+//! {
+//!     "operations": [
+//!         { "op": "replace", "into": "0A", "ids": ["0B", "0C"] },
+//!         { "op": "merge", "ids": ["10", "11", "12"], "keep": "10" },
+//!         { "op": "swap", "ids": ["20", "21"] },
+//!         { "op": "remove-unused" },
+//!         { "op": "rename-file", "from": "kick.wav", "to": "kick_01.wav" },
+//!         { "op": "set-header", "header": "TITLE", "value": "Example [ANOTHER]" }
+//!     ]
+//! }
+//! ```
+
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bms::{as_keysound_id, as_str},
+    channel::ReplacePolicy,
+    chart::BMSFile,
+    error::BmsError,
+    header::HeaderKind,
+    package::SongPackage,
+    resource::FileResolver,
+};
+
+/// A list of operations, applied in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Modifications {
+    pub operations: Vec<Operation>,
+}
+
+/// A single edit. Keysound IDs are written as two character strings such as `"0A"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Operation {
+    /// Rewrites the notes using `ids` to use `into`, and removes the definitions no longer used.
+    Replace { into: String, ids: Vec<String> },
+    /// Merges `ids` into `keep`, or into the most used of them if `keep` is missing.
+    Merge {
+        ids: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keep: Option<String>,
+    },
+    /// Swaps the files of two keysounds.
+    Swap { ids: [String; 2] },
+    /// Removes the definitions no note plays in any branch.
+    RemoveUnused,
+    /// Renames a keysound file, both on disk and in every definition using it. Files that other
+    /// charts in the folder use are never renamed.
+    RenameFile { from: String, to: String },
+    /// Sets a standard header such as `TITLE`, adding it if it is missing.
+    SetHeader { header: String, value: String },
+}
+
+/// A file that has to be renamed on disk for the modified chart to find it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl FileRename {
+    /// The rename that undoes this one.
+    pub fn reversed(&self) -> Self {
+        Self {
+            from: self.to.clone(),
+            to: self.from.clone(),
+        }
+    }
+}
+
+impl Modifications {
+    pub fn from_path(path: &Path) -> Result<Self, BmsError> {
+        let bytes = fs::read(path).map_err(|e| BmsError::io(path, e))?;
+
+        serde_json::from_slice(&bytes).map_err(|source| BmsError::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Applies every operation to the chart. Each operation is checked against the chart as
+    /// the previous ones left it, and if any of them fails the chart is left untouched.
+    ///
+    /// Files are never renamed here. The renames the chart now depends on are returned so they
+    /// can be carried out with [`rename_files`] once the chart is ready to be saved.
+    pub fn apply(&self, bms: &mut BMSFile) -> Result<Vec<FileRename>, BmsError> {
+        let mut modified = bms.clone();
+        let mut renames = Vec::new();
+
+        for (index, operation) in self.operations.iter().enumerate() {
+            operation
+                .apply(&mut modified, &mut renames)
+                .map_err(|message| BmsError::Modification {
+                    operation: index + 1,
+                    message,
+                })?;
+        }
+
+        *bms = modified;
+
+        Ok(renames)
+    }
+}
+
+impl Operation {
+    fn apply(&self, bms: &mut BMSFile, renames: &mut Vec<FileRename>) -> Result<(), String> {
+        match self {
+            Operation::Replace { into, ids } => {
+                let into = defined_id(bms, into)?;
+                let ids = defined_ids(bms, ids)?;
+
+                if ids.is_empty() {
+                    return Err("No keysounds to replace".to_string());
+                }
+
+                bms.merge_keysounds(into, &ids, &ReplacePolicy::default());
+            }
+            Operation::Merge { ids, keep } => {
+                let mut ids = defined_ids(bms, ids)?;
+                ids.sort();
+                ids.dedup();
+
+                if ids.len() < 2 {
+                    return Err("At least two keysounds are needed for a merge".to_string());
+                }
+
+                let target = match keep {
+                    Some(keep) => {
+                        let keep = defined_id(bms, keep)?;
+
+                        if !ids.contains(&keep) {
+                            return Err(format!(
+                                "{} is not one of the merged keysounds",
+                                as_str(keep)
+                            ));
+                        }
+
+                        keep
+                    }
+                    None => bms
+                        .most_used_keysound(&ids)
+                        .expect("There is at least one keysound to merge."),
+                };
+
+                bms.merge_keysounds(target, &ids, &ReplacePolicy::default());
+            }
+            Operation::Swap { ids: [a, b] } => {
+                let a = defined_id(bms, a)?;
+                let b = defined_id(bms, b)?;
+
+                if a == b {
+                    return Err(format!("Can't swap {} with itself", as_str(a)));
+                }
+
+                bms.swap_keysounds(a, b);
+            }
+            Operation::RemoveUnused => {
+                bms.remove_unused_keysounds();
+            }
+            Operation::RenameFile { from, to } => {
+                let to = to.trim();
+
+                if to.is_empty() || to.contains(['\r', '\n']) {
+                    return Err(format!("{:?} is not a valid file name", to));
+                }
+
+                if bms
+                    .keysounds()
+                    .any(|keysound| keysound.keysound_file() == to)
+                {
+                    return Err(format!("{} is already used by another keysound", to));
+                }
+
+                if bms.rename_keysound_file(from, to) == 0 {
+                    return Err(format!("No keysound uses {}", from));
+                }
+
+                let resolver = FileResolver::new(bms.folder());
+                let from_path = resolver.resolve(from);
+                let mut to_path = resolver.resolve(to);
+
+                // A file found under another extension keeps it, so players still find it
+                if let Some(extension) = from_path.extension().and_then(|e| e.to_str())
+                    && !Path::new(from)
+                        .extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|written| written.eq_ignore_ascii_case(extension))
+                {
+                    to_path.set_extension(extension);
+                }
+
+                if to_path.exists() {
+                    return Err(format!("{} already exists", to_path.display()));
+                }
+
+                // A file renamed earlier in the batch is still on disk under its first name
+                if let Some(rename) = renames.iter_mut().find(|rename| rename.to == from_path) {
+                    rename.to = to_path;
+                } else if from_path.exists() {
+                    // The other charts of the song would lose the file if it were renamed
+                    let package =
+                        SongPackage::from_folder(bms.folder()).map_err(|e| e.to_string())?;
+
+                    if let Some(other) = package
+                        .charts_using(&from_path)
+                        .into_iter()
+                        .find(|chart| chart.path().file_name() != bms.path().file_name())
+                    {
+                        return Err(format!(
+                            "{} is also used by {}",
+                            from,
+                            other.path().display()
+                        ));
+                    }
+
+                    renames.push(FileRename {
+                        from: from_path,
+                        to: to_path,
+                    });
+                }
+            }
+            Operation::SetHeader { header, value } => {
+                let kind = HeaderKind::from_command(header.trim_start_matches('#'))
+                    .ok_or_else(|| format!("Unknown header {}", header))?;
+
+                if value.contains(['\r', '\n']) {
+                    return Err(format!("The value of #{} has a line break", kind.command()));
+                }
+
+                bms.set_header(kind, value.trim());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Replace { into, ids } => {
+                write!(f, "replace {} with {}", ids.join(", "), into)
+            }
+            Operation::Merge {
+                ids,
+                keep: Some(keep),
+            } => {
+                write!(f, "merge {} into {}", ids.join(", "), keep)
+            }
+            Operation::Merge { ids, keep: None } => write!(f, "merge {}", ids.join(", ")),
+            Operation::Swap { ids: [a, b] } => write!(f, "swap {} and {}", a, b),
+            Operation::RemoveUnused => write!(f, "remove unused keysounds"),
+            Operation::RenameFile { from, to } => write!(f, "rename {} to {}", from, to),
+            Operation::SetHeader { header, value } => write!(f, "set {} to {}", header, value),
+        }
+    }
+}
+
+fn defined_id(bms: &BMSFile, id: &str) -> Result<u64, String> {
+    let keysound_id = as_keysound_id(id.trim())
+        .ok_or_else(|| format!("{:?} is not a two character keysound ID", id))?;
+
+    if !bms.has_keysound(keysound_id) {
+        return Err(format!("{} isn't defined", as_str(keysound_id)));
+    }
+
+    Ok(keysound_id)
+}
+
+fn defined_ids(bms: &BMSFile, ids: &[String]) -> Result<Vec<u64>, String> {
+    ids.iter().map(|id| defined_id(bms, id)).collect()
+}
+
+/// Carries out the renames returned by [`Modifications::apply`]. If one of them fails, the
+/// files already renamed are moved back.
+pub fn rename_files(renames: &[FileRename]) -> Result<(), BmsError> {
+    for (index, rename) in renames.iter().enumerate() {
+        if let Err(e) = fs::rename(&rename.from, &rename.to) {
+            renames[..index].iter().rev().for_each(|done| {
+                let _ = fs::rename(&done.to, &done.from);
+            });
+
+            return Err(BmsError::io(&rename.from, e));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bms::as_id, testing::TempFolder};
+
+    use super::*;
+
+    const CHART: &str = "#TITLE old\n#WAV01 kick.wav\n#WAV02 kick2.wav\n#WAV03 snare.wav\n\
+        #WAV04 hat.wav\n#WAV05 unused.wav\n#00111:01020304\n#00112:0304\n";
+
+    fn chart() -> BMSFile {
+        BMSFile::from_bytes(Path::new("missing_folder/chart.bms"), CHART.as_bytes())
+    }
+
+    fn text(bms: &BMSFile) -> String {
+        String::from_utf8(bms.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_modifications() {
+        let json = r#"{"operations": [
+            {"op": "replace", "into": "01", "ids": ["02"]},
+            {"op": "merge", "ids": ["03", "04"]},
+            {"op": "swap", "ids": ["01", "03"]},
+            {"op": "remove-unused"},
+            {"op": "rename-file", "from": "a.wav", "to": "b.wav"},
+            {"op": "set-header", "header": "TITLE", "value": "new"}
+        ]}"#;
+
+        let modifications: Modifications = serde_json::from_str(json).unwrap();
+
+        assert_eq!(modifications.operations.len(), 6);
+        assert_eq!(
+            modifications.operations[1],
+            Operation::Merge {
+                ids: vec!["03".to_string(), "04".to_string()],
+                keep: None
+            }
+        );
+
+        let round_trip = serde_json::to_string(&modifications).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Modifications>(&round_trip).unwrap(),
+            modifications
+        );
+
+        assert!(
+            serde_json::from_str::<Modifications>(r#"{"operations": [{"op": "nope"}]}"#).is_err()
+        );
+        assert!(
+            serde_json::from_str::<Modifications>(
+                r#"{"operations": [{"op": "swap", "ids": ["01"]}]}"#
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_apply_modifications() {
+        let mut bms = chart();
+
+        let modifications = Modifications {
+            operations: vec![
+                Operation::Replace {
+                    into: "01".to_string(),
+                    ids: vec!["02".to_string()],
+                },
+                Operation::Swap {
+                    ids: ["03".to_string(), "04".to_string()],
+                },
+                Operation::RemoveUnused,
+                Operation::RenameFile {
+                    from: "kick.wav".to_string(),
+                    to: "kick_01.wav".to_string(),
+                },
+                Operation::SetHeader {
+                    header: "#title".to_string(),
+                    value: "new".to_string(),
+                },
+            ],
+        };
+
+        // The files don't exist, so there is nothing to rename on disk
+        assert_eq!(modifications.apply(&mut bms).unwrap(), vec![]);
+
+        assert_eq!(
+            text(&bms),
+            "#TITLE new\n#WAV01 kick_01.wav\n#WAV03 hat.wav\n#WAV04 snare.wav\n\
+            #00111:01010304\n#00112:0304\n"
+        );
+    }
+
+    #[test]
+    fn test_failed_modifications_leave_the_chart_untouched() {
+        let mut bms = chart();
+
+        let modifications = Modifications {
+            operations: vec![
+                Operation::Replace {
+                    into: "01".to_string(),
+                    ids: vec!["02".to_string()],
+                },
+                // 02 was removed by the replacement
+                Operation::Merge {
+                    ids: vec!["02".to_string(), "03".to_string()],
+                    keep: None,
+                },
+            ],
+        };
+
+        match modifications.apply(&mut bms) {
+            Err(BmsError::Modification { operation, message }) => {
+                assert_eq!(operation, 2);
+                assert_eq!(message, "02 isn't defined");
+            }
+            result => panic!("Expected the merge to fail, got {:?}", result),
+        }
+
+        assert_eq!(text(&bms), CHART);
+        assert!(bms.has_keysound(as_id("02").unwrap()));
+
+        for operation in [
+            Operation::Swap {
+                ids: ["01".to_string(), "1".to_string()],
+            },
+            Operation::Merge {
+                ids: vec!["01".to_string(), "02".to_string()],
+                keep: Some("03".to_string()),
+            },
+            Operation::RenameFile {
+                from: "missing.wav".to_string(),
+                to: "b.wav".to_string(),
+            },
+            Operation::RenameFile {
+                from: "kick.wav".to_string(),
+                to: "snare.wav".to_string(),
+            },
+            Operation::SetHeader {
+                header: "WAV01".to_string(),
+                value: "a.wav".to_string(),
+            },
+        ] {
+            let modifications = Modifications {
+                operations: vec![operation],
+            };
+
+            assert!(modifications.apply(&mut bms).is_err());
+            assert_eq!(text(&bms), CHART);
+        }
+    }
+
+    #[test]
+    fn test_renames_never_break_other_charts() {
+        let folder = TempFolder::new("modifications");
+
+        let path = folder.join("normal.bms");
+        fs::write(
+            &path,
+            "#WAV01 kick.wav\n#WAV02 snare.wav\n#WAV03 Snare.WAV\n#00111:010203\n",
+        )
+        .unwrap();
+        fs::write(folder.join("hyper.bms"), "#WAV01 kick.wav\n#00111:01\n").unwrap();
+
+        for file in ["kick.wav", "snare.wav"] {
+            fs::write(folder.join(file), b"").unwrap();
+        }
+
+        let mut bms = BMSFile::from_path(&path).unwrap();
+
+        let rename = |from: &str, to: &str| Modifications {
+            operations: vec![Operation::RenameFile {
+                from: from.to_string(),
+                to: to.to_string(),
+            }],
+        };
+
+        match rename("kick.wav", "kick_01.wav").apply(&mut bms) {
+            Err(BmsError::Modification { message, .. }) => {
+                assert!(
+                    message.starts_with("kick.wav is also used by"),
+                    "{}",
+                    message
+                );
+            }
+            result => panic!("Expected the rename to fail, got {:?}", result),
+        }
+
+        let renames = rename("snare.wav", "snare_01.wav").apply(&mut bms).unwrap();
+        assert_eq!(
+            renames,
+            vec![FileRename {
+                from: folder.join("snare.wav"),
+                to: folder.join("snare_01.wav"),
+            }]
+        );
+
+        // Every definition players would find the file through is renamed with it
+        assert_eq!(
+            text(&bms),
+            "#WAV01 kick.wav\n#WAV02 snare_01.wav\n#WAV03 snare_01.wav\n#00111:010203\n"
+        );
+    }
+}
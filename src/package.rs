@@ -0,0 +1,277 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    chart::BMSFile,
+    error::BmsError,
+    line::Keysound,
+    resource::{FileResolver, FileStatus, FileUsage, ResourceKind, classify_files},
+};
+
+/// The extensions of the charts that make up a song package.
+pub const CHART_EXTENSIONS: [&str; 4] = ["bms", "bme", "bml", "pms"];
+
+/// Every chart in a song folder. The difficulties of a song usually share one set of keysounds,
+/// so a file is only unused once none of the charts refer to it.
+#[derive(Debug, Clone)]
+pub struct SongPackage {
+    folder: PathBuf,
+    charts: Vec<BMSFile>,
+}
+
+impl SongPackage {
+    /// Loads every chart in a folder, sorted by file name.
+    pub fn from_folder(folder: &Path) -> Result<Self, BmsError> {
+        let entries = fs::read_dir(folder).map_err(|e| BmsError::io(folder, e))?;
+
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_chart(path))
+            .collect();
+
+        paths.sort();
+
+        let charts = paths
+            .iter()
+            .map(|path| BMSFile::from_path(path))
+            .collect::<Result<Vec<BMSFile>, BmsError>>()?;
+
+        Ok(Self {
+            folder: folder.to_path_buf(),
+            charts,
+        })
+    }
+
+    /// Loads the package a chart belongs to. The chart is included even if its extension isn't
+    /// one of [`CHART_EXTENSIONS`].
+    pub fn for_chart(chart: &Path) -> Result<Self, BmsError> {
+        let folder = match chart.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let mut package = Self::from_folder(folder)?;
+
+        if package.chart(chart).is_none() {
+            package.charts.push(BMSFile::from_path(
+                &folder.join(chart.file_name().unwrap_or_default()),
+            )?);
+        }
+
+        Ok(package)
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    pub fn charts(&self) -> &[BMSFile] {
+        &self.charts
+    }
+
+    /// Finds a chart of the package by its file name.
+    pub fn chart(&self, path: &Path) -> Option<&BMSFile> {
+        self.charts
+            .iter()
+            .find(|chart| chart.path().file_name() == path.file_name())
+    }
+
+    pub fn chart_mut(&mut self, path: &Path) -> Option<&mut BMSFile> {
+        self.charts
+            .iter_mut()
+            .find(|chart| chart.path().file_name() == path.file_name())
+    }
+
+    /// Replaces a chart of the package with an edited copy, or adds it if it is new.
+    pub fn update(&mut self, chart: BMSFile) {
+        match self.chart_mut(chart.path()) {
+            Some(existing) => *existing = chart,
+            None => self.charts.push(chart),
+        }
+    }
+
+    /// The files that keysounds in any of the charts refer to.
+    pub fn used_files(&self) -> HashSet<PathBuf> {
+        let resolver = FileResolver::new(&self.folder);
+
+        self.charts
+            .iter()
+            .flat_map(|chart| chart.keysounds())
+            .map(|keysound| resolver.resolve(keysound.keysound_file()))
+            .collect()
+    }
+
+    /// The charts with a keysound that refers to `file`.
+    pub fn charts_using(&self, file: &Path) -> Vec<&BMSFile> {
+        let resolver = FileResolver::new(&self.folder);
+
+        self.charts
+            .iter()
+            .filter(|chart| {
+                chart
+                    .keysounds()
+                    .any(|keysound| resolver.resolve(keysound.keysound_file()) == file)
+            })
+            .collect()
+    }
+
+    /// Finds the files of the given (removed) keysounds that nothing in any chart refers to.
+    pub fn get_orphaned_files(&self, removed: &[Keysound]) -> Vec<PathBuf> {
+        let resolver = FileResolver::new(&self.folder);
+
+        let references: HashSet<PathBuf> = self
+            .charts
+            .iter()
+            .flat_map(|chart| chart.resources())
+            .map(|resource| resolver.resolve(&resource.file))
+            .collect();
+
+        let mut orphaned_files: Vec<PathBuf> = removed
+            .iter()
+            .map(|keysound| resolver.resolve(keysound.keysound_file()))
+            .filter(|file| !references.contains(file))
+            .collect();
+
+        orphaned_files.sort();
+        orphaned_files.dedup();
+        orphaned_files
+    }
+
+    /// Sorts the files of the song folder by whether any chart refers to them.
+    pub fn classify_files(&self) -> Result<Vec<FileUsage>, BmsError> {
+        let charts: Vec<&BMSFile> = self.charts.iter().collect();
+
+        classify_files(&self.folder, &charts)
+    }
+
+    /// Finds the audio files in the song folder that nothing in any chart refers to. Images,
+    /// videos and unknown files are never included.
+    pub fn get_unused_files(&self) -> Result<Vec<PathBuf>, BmsError> {
+        Ok(self
+            .classify_files()?
+            .into_iter()
+            .filter(|usage| {
+                usage.status == FileStatus::Unreferenced && usage.kind == Some(ResourceKind::Audio)
+            })
+            .map(|usage| usage.path)
+            .collect())
+    }
+
+    /// The other charts that define every one of `ids` with the same file as `chart` does, so
+    /// that the same change can be made to them.
+    pub fn charts_sharing_keysounds(&self, chart: &BMSFile, ids: &[u64]) -> Vec<&BMSFile> {
+        let files: Vec<Option<&str>> = ids
+            .iter()
+            .map(|id| {
+                chart
+                    .get_keysound(*id)
+                    .map(|keysound| keysound.keysound_file())
+            })
+            .collect();
+
+        self.charts
+            .iter()
+            .filter(|other| other.path().file_name() != chart.path().file_name())
+            .filter(|other| {
+                ids.iter().zip(&files).all(|(id, file)| {
+                    file.is_some()
+                        && other
+                            .get_keysound(*id)
+                            .map(|keysound| keysound.keysound_file())
+                            == *file
+                })
+            })
+            .collect()
+    }
+}
+
+/// Checks whether a file is a chart by its extension, ignoring case.
+pub fn is_chart(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            CHART_EXTENSIONS
+                .iter()
+                .any(|chart_extension| extension.eq_ignore_ascii_case(chart_extension))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TempFolder;
+
+    use super::*;
+
+    #[test]
+    fn test_package_file_usage() {
+        let folder = TempFolder::new("package");
+
+        fs::write(
+            folder.join("normal.bms"),
+            "#WAV01 kick.wav\n#WAV02 snare.wav\n#00111:0102\n",
+        )
+        .unwrap();
+        fs::write(
+            folder.join("hyper.BME"),
+            "#WAV01 kick.wav\n#WAV02 snare.wav\n#WAV03 hat.ogg\n#00111:010203\n",
+        )
+        .unwrap();
+        fs::write(folder.join("notes.txt"), "").unwrap();
+
+        for file in ["kick.wav", "snare.wav", "hat.ogg", "unused.ogg"] {
+            fs::write(folder.join(file), b"").unwrap();
+        }
+
+        let mut package = SongPackage::from_folder(&folder).unwrap();
+
+        assert_eq!(package.charts().len(), 2);
+        assert_eq!(
+            package.get_unused_files().unwrap(),
+            vec![folder.join("unused.ogg")]
+        );
+
+        // Both charts map 01 and 02 to the same files, but only one defines 03
+        let normal = Path::new("normal.bms");
+        let hyper = Path::new("hyper.BME");
+
+        let normal_chart = package.chart(normal).unwrap().clone();
+        let hyper_chart = package.chart(hyper).unwrap().clone();
+
+        assert_eq!(
+            package
+                .charts_sharing_keysounds(&normal_chart, &[1, 2])
+                .len(),
+            1
+        );
+        assert!(
+            package
+                .charts_sharing_keysounds(&hyper_chart, &[1, 3])
+                .is_empty()
+        );
+
+        // Removing kick from one chart leaves it in use by the other, unlike hat
+        let removed: Vec<Keysound> = [normal_chart.get_keysound(1), hyper_chart.get_keysound(3)]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+
+        package
+            .chart_mut(normal)
+            .unwrap()
+            .retain_keysounds(|keysound| keysound.keysound_id() != 1);
+
+        let mut hyper_chart = hyper_chart;
+        hyper_chart.retain_keysounds(|keysound| keysound.keysound_id() != 3);
+        package.update(hyper_chart);
+
+        assert_eq!(
+            package.get_orphaned_files(&removed),
+            vec![folder.join("hat.ogg")]
+        );
+        assert_eq!(package.charts_using(&folder.join("kick.wav")).len(), 1);
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup::{next_timestamp, timestamp},
+    bms::as_str,
+    error::BmsError,
+};
+
+/// Where removed audio is kept, relative to the song folder.
+pub const QUARANTINE_DIR: &str = ".bmsjoin/quarantine";
+
+const MANIFEST: &str = "manifest.json";
+
+/// Quarantined files end in this so that players scanning the song folder skip them.
+const QUARANTINE_EXTENSION: &str = "removed";
+
+/// A file that was removed from a song folder, and where it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantinedFile {
+    /// Where the file was before it was removed.
+    pub original: PathBuf,
+    /// Where the file is kept until it is restored or purged.
+    pub stored: PathBuf,
+    /// The chart that stopped using the file, if it was removed for one.
+    pub chart: Option<PathBuf>,
+    /// The IDs of the keysounds that played the file.
+    pub keysounds: Vec<String>,
+    /// When the file was removed, as `YYYYMMDD-HHMMSS-mmm` in UTC.
+    pub removed: String,
+}
+
+impl QuarantinedFile {
+    /// The file with its paths relative to the song folder, or to the quarantine for where it
+    /// is stored, as they are saved.
+    pub(crate) fn relative_to(&self, folder: &Path) -> Self {
+        let relative = |path: &Path, base: &Path| {
+            path.strip_prefix(base)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| path.to_path_buf())
+        };
+
+        Self {
+            original: relative(&self.original, folder),
+            stored: relative(&self.stored, &folder.join(QUARANTINE_DIR)),
+            chart: self.chart.as_deref().map(|chart| relative(chart, folder)),
+            ..self.clone()
+        }
+    }
+
+    /// Joins paths saved by [`QuarantinedFile::relative_to`] back onto the song folder.
+    pub(crate) fn resolve(self, folder: &Path) -> Self {
+        Self {
+            original: folder.join(&self.original),
+            stored: folder.join(QUARANTINE_DIR).join(&self.stored),
+            chart: self.chart.map(|chart| folder.join(chart)),
+            ..self
+        }
+    }
+}
+
+/// The files removed from one song folder.
+///
+/// Files are moved into `<folder>/.bmsjoin/quarantine` instead of being deleted, and a manifest
+/// next to them records what each one was. The manifest stores paths relative to the song
+/// folder, so the folder can be moved around with its quarantine.
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    folder: PathBuf,
+    files: Vec<QuarantinedFile>,
+}
+
+impl Quarantine {
+    /// Opens the quarantine of a song folder, reading its manifest if there is one.
+    pub fn new(folder: &Path) -> Result<Self, BmsError> {
+        // Absolute paths let files be matched however the folder was named
+        let folder = absolute(folder)?;
+
+        let mut quarantine = Self {
+            folder: folder.clone(),
+            files: Vec::new(),
+        };
+
+        let manifest = quarantine.manifest_path();
+
+        match fs::read(&manifest) {
+            Ok(bytes) => {
+                let files: Vec<QuarantinedFile> =
+                    serde_json::from_slice(&bytes).map_err(|source| BmsError::Json {
+                        path: manifest.clone(),
+                        source,
+                    })?;
+
+                quarantine.files = files
+                    .into_iter()
+                    .map(|file| file.resolve(&folder))
+                    .collect();
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(BmsError::io(&manifest, e)),
+        }
+
+        Ok(quarantine)
+    }
+
+    /// The quarantine of the folder a chart is in.
+    pub fn for_chart(chart: &Path) -> Result<Self, BmsError> {
+        Self::new(chart.parent().unwrap_or(Path::new("")))
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.folder.join(QUARANTINE_DIR)
+    }
+
+    /// The quarantined files, oldest first.
+    pub fn files(&self) -> &[QuarantinedFile] {
+        &self.files
+    }
+
+    pub fn contains(&self, file: &QuarantinedFile) -> bool {
+        self.files.contains(file)
+    }
+
+    /// Moves a file into the quarantine, noting the chart and keysounds it was removed for.
+    pub fn quarantine(
+        &mut self,
+        file: &Path,
+        chart: Option<&Path>,
+        keysounds: &[u64],
+    ) -> Result<QuarantinedFile, BmsError> {
+        let keysounds = keysounds.iter().map(|id| as_str(*id)).collect();
+        let chart = chart.map(absolute).transpose()?;
+
+        self.add(file, chart, keysounds, None)
+    }
+
+    /// Moves a file that was restored back into the quarantine, keeping its details. The file
+    /// is stored under the same name as before if that name is still free, so that anything
+    /// recording where it was stored stays correct.
+    pub fn requarantine(&mut self, file: &QuarantinedFile) -> Result<QuarantinedFile, BmsError> {
+        self.add(
+            &file.original,
+            file.chart.clone(),
+            file.keysounds.clone(),
+            Some(file),
+        )
+    }
+
+    /// Moves a file back to where it was removed from. Fails if another file has taken its
+    /// place since.
+    pub fn restore(&mut self, file: &QuarantinedFile) -> Result<(), BmsError> {
+        let index = self.position(file)?;
+
+        if file.original.exists() {
+            return Err(BmsError::io(
+                &file.original,
+                io::Error::from(io::ErrorKind::AlreadyExists),
+            ));
+        }
+
+        if let Some(parent) = file.original.parent() {
+            fs::create_dir_all(parent).map_err(|e| BmsError::io(parent, e))?;
+        }
+
+        move_file(&file.stored, &file.original)?;
+
+        self.files.remove(index);
+        self.save()
+    }
+
+    /// Deletes a quarantined file for good.
+    pub fn purge(&mut self, file: &QuarantinedFile) -> Result<(), BmsError> {
+        let index = self.position(file)?;
+
+        match fs::remove_file(&file.stored) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(BmsError::io(&file.stored, e)),
+        }
+
+        self.files.remove(index);
+        self.save()
+    }
+
+    fn add(
+        &mut self,
+        file: &Path,
+        chart: Option<PathBuf>,
+        keysounds: Vec<String>,
+        previous: Option<&QuarantinedFile>,
+    ) -> Result<QuarantinedFile, BmsError> {
+        let file = absolute(file)?;
+        let file = file.as_path();
+
+        let file_name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let relative_parent = file
+            .strip_prefix(&self.folder)
+            .ok()
+            .and_then(|relative| relative.parent())
+            .unwrap_or(Path::new(""));
+
+        let dir = self.dir().join(relative_parent);
+
+        fs::create_dir_all(&dir).map_err(|e| BmsError::io(&dir, e))?;
+
+        // Files removed within the same millisecond get a counter, as backups do
+        let (mut removed, mut stored) = match previous {
+            Some(previous) if !previous.stored.exists() => {
+                (previous.removed.clone(), previous.stored.clone())
+            }
+            _ => {
+                let removed = timestamp(SystemTime::now());
+                let stored = dir.join(format!(
+                    "{}.{}.{}",
+                    file_name, removed, QUARANTINE_EXTENSION
+                ));
+
+                (removed, stored)
+            }
+        };
+
+        while stored.exists() {
+            removed = next_timestamp(removed.clone(), Some(&removed));
+            stored = dir.join(format!(
+                "{}.{}.{}",
+                file_name, removed, QUARANTINE_EXTENSION
+            ));
+        }
+
+        move_file(file, &stored)?;
+
+        let quarantined = QuarantinedFile {
+            original: file.to_path_buf(),
+            stored,
+            chart,
+            keysounds,
+            removed,
+        };
+
+        self.files.push(quarantined.clone());
+        self.save()?;
+
+        Ok(quarantined)
+    }
+
+    fn position(&self, file: &QuarantinedFile) -> Result<usize, BmsError> {
+        self.files
+            .iter()
+            .position(|f| f == file)
+            .ok_or_else(|| BmsError::io(&file.stored, io::Error::from(io::ErrorKind::NotFound)))
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir().join(MANIFEST)
+    }
+
+    fn save(&self) -> Result<(), BmsError> {
+        let dir = self.dir();
+        let manifest = self.manifest_path();
+
+        let files: Vec<QuarantinedFile> = self
+            .files
+            .iter()
+            .map(|file| file.relative_to(&self.folder))
+            .collect();
+
+        fs::create_dir_all(&dir).map_err(|e| BmsError::io(&dir, e))?;
+
+        let json = serde_json::to_vec_pretty(&files).map_err(|source| BmsError::Json {
+            path: manifest.clone(),
+            source,
+        })?;
+
+        fs::write(&manifest, json).map_err(|e| BmsError::io(&manifest, e))
+    }
+}
+
+/// Makes a path absolute without touching the file system. An empty path is the current
+/// folder, as it is for the parent of a bare file name.
+pub(crate) fn absolute(path: &Path) -> Result<PathBuf, BmsError> {
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+
+    std::path::absolute(path).map_err(|e| BmsError::io(path, e))
+}
+
+/// Renames a file, copying it instead if it has to cross file systems.
+fn move_file(from: &Path, to: &Path) -> Result<(), BmsError> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to).map_err(|e| BmsError::io(from, e))?;
+        fs::remove_file(from).map_err(|e| BmsError::io(from, e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TempFolder;
+
+    use super::*;
+
+    #[test]
+    fn test_quarantine_restore_and_purge() {
+        let folder = TempFolder::new("quarantine");
+        fs::create_dir_all(folder.join("sounds")).unwrap();
+
+        let chart = folder.join("chart.bms");
+        let kick = folder.join("sounds").join("kick.wav");
+        let snare = folder.join("snare.ogg");
+
+        fs::write(&kick, b"kick").unwrap();
+        fs::write(&snare, b"snare").unwrap();
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        let kick_entry = quarantine
+            .quarantine(&kick, Some(&chart), &[1, 37])
+            .unwrap();
+        let snare_entry = quarantine.quarantine(&snare, None, &[]).unwrap();
+
+        assert!(!kick.exists());
+        assert!(kick_entry.stored.starts_with(folder.join(QUARANTINE_DIR)));
+        assert_eq!(kick_entry.keysounds, vec!["01", "11"]);
+
+        // The manifest keeps the details, relative to the song folder
+        let manifest = fs::read_to_string(folder.join(QUARANTINE_DIR).join(MANIFEST)).unwrap();
+        assert!(manifest.contains("chart.bms"));
+        assert!(!manifest.contains(folder.to_str().unwrap()));
+
+        let mut quarantine = Quarantine::new(&folder).unwrap();
+        assert_eq!(
+            quarantine.files(),
+            [kick_entry.clone(), snare_entry.clone()]
+        );
+
+        // Nothing is overwritten by a restore
+        fs::write(&snare, b"new snare").unwrap();
+        assert!(quarantine.restore(&snare_entry).is_err());
+        fs::remove_file(&snare).unwrap();
+
+        quarantine.restore(&snare_entry).unwrap();
+        assert_eq!(fs::read(&snare).unwrap(), b"snare");
+
+        quarantine.purge(&kick_entry).unwrap();
+        assert!(!kick_entry.stored.exists());
+        assert!(quarantine.files().is_empty());
+        assert!(Quarantine::new(&folder).unwrap().files().is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{bms::as_id, chart::BMSFile, error::BmsError, package::is_chart};
+
+pub const AUDIO_EXTENSIONS: [&str; 6] = ["wav", "ogg", "flac", "mp3", "aiff", "aif"];
+pub const IMAGE_EXTENSIONS: [&str; 6] = ["bmp", "png", "jpg", "jpeg", "gif", "tga"];
+pub const VIDEO_EXTENSIONS: [&str; 7] = ["mpg", "mpeg", "avi", "mp4", "wmv", "webm", "m4v"];
+
+/// The file that missing keysounds are pointed at when they are replaced with silence.
+pub const SILENT_PLACEHOLDER: &str = "silence.wav";
+
+/// The audio extensions players try, in order, when a keysound's file doesn't exist as written.
+const AUDIO_FALLBACKS: [&str; 4] = ["wav", "ogg", "flac", "mp3"];
+
+/// The working folder of bmsjoin, which is never scanned for resources.
+const WORKING_DIR: &str = ".bmsjoin";
+
+/// The kinds of file a chart can refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Audio,
+    Image,
+    Video,
+}
+
+impl ResourceKind {
+    /// Works out the kind of a file from its extension, ignoring case.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        if AUDIO_EXTENSIONS.contains(&extension.as_str()) {
+            Some(ResourceKind::Audio)
+        } else if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            Some(ResourceKind::Image)
+        } else if VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+            Some(ResourceKind::Video)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for ResourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceKind::Audio => write!(f, "audio"),
+            ResourceKind::Image => write!(f, "image"),
+            ResourceKind::Video => write!(f, "video"),
+        }
+    }
+}
+
+/// A file that a chart refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    /// The command that refers to the file, such as `WAV01` or `STAGEFILE`.
+    pub command: String,
+    /// The file as it is written in the chart, relative to the chart's folder.
+    pub file: String,
+}
+
+impl Resource {
+    /// Parses a `#BMPxx` definition, which the chart otherwise keeps as a generic line.
+    pub(crate) fn from_bmp_line(line: &str) -> Option<Self> {
+        if !line
+            .get(..4)
+            .is_some_and(|command| command.eq_ignore_ascii_case("#BMP"))
+        {
+            return None;
+        }
+
+        let id = line
+            .get(4..6)
+            .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric()))?;
+        let file = line[6..].trim();
+
+        if file.is_empty() || !line[6..].starts_with(char::is_whitespace) || as_id(id).is_err() {
+            return None;
+        }
+
+        Some(Self {
+            command: format!("BMP{}", id.to_ascii_uppercase()),
+            file: file.to_string(),
+        })
+    }
+
+    pub fn kind(&self) -> Option<ResourceKind> {
+        ResourceKind::from_path(Path::new(&self.file))
+    }
+}
+
+/// Whether any chart refers to a file in the song folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Referenced,
+    /// An audio, image or video file that no chart refers to.
+    Unreferenced,
+    /// A file that isn't a known kind of resource, which is never treated as unused.
+    Unknown,
+}
+
+impl Display for FileStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileStatus::Referenced => write!(f, "referenced"),
+            FileStatus::Unreferenced => write!(f, "unreferenced"),
+            FileStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A file in a song folder and whether it is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileUsage {
+    pub path: PathBuf,
+    pub kind: Option<ResourceKind>,
+    pub status: FileStatus,
+}
+
+/// Finds the files that charts refer to the way BMS players do.
+///
+/// Charts are often written on Windows, so `\` is accepted as a separator and names are
+/// matched ignoring case. A keysound whose file is missing is also matched to a file with the
+/// same name and another audio extension, such as `piano01.ogg` for `piano01.wav`. An exact
+/// match is always preferred.
+///
+/// Folder listings are cached, so a resolver should not be kept across changes to the folder.
+#[derive(Debug)]
+pub struct FileResolver {
+    folder: PathBuf,
+    /// The entries of each folder looked in so far, and whether they are folders.
+    listings: RefCell<HashMap<PathBuf, Vec<(PathBuf, bool)>>>,
+}
+
+impl FileResolver {
+    pub fn new(folder: &Path) -> Self {
+        Self {
+            folder: folder.to_path_buf(),
+            listings: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Finds the file a chart refers to, or None if there is no such file.
+    pub fn find(&self, file: &str) -> Option<PathBuf> {
+        let file = file.trim().replace('\\', "/");
+        let mut components: Vec<&str> = file
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect();
+
+        let name = components.pop()?;
+        let mut dir = self.folder.clone();
+
+        for component in components {
+            dir = if component == ".." {
+                dir.join(component)
+            } else {
+                self.find_entry(&dir, component, true)?
+            };
+        }
+
+        if let Some(path) = self.find_entry(&dir, name, false) {
+            return Some(path);
+        }
+
+        let (stem, extension) = name.rsplit_once('.')?;
+
+        if ResourceKind::from_path(Path::new(name)) != Some(ResourceKind::Audio) {
+            return None;
+        }
+
+        AUDIO_FALLBACKS
+            .iter()
+            .filter(|fallback| !fallback.eq_ignore_ascii_case(extension))
+            .find_map(|fallback| self.find_entry(&dir, &format!("{}.{}", stem, fallback), false))
+    }
+
+    /// Where a file a chart refers to is on disk: the file [`find`](Self::find) picks if there
+    /// is one, otherwise the path as written with `\` read as a separator.
+    pub fn resolve(&self, file: &str) -> PathBuf {
+        self.find(file)
+            .unwrap_or_else(|| self.folder.join(file.trim().replace('\\', "/")))
+    }
+
+    /// Looks up a file or folder in `dir` by name, preferring the exact name over one that only
+    /// differs in case.
+    fn find_entry(&self, dir: &Path, name: &str, is_dir: bool) -> Option<PathBuf> {
+        let mut listings = self.listings.borrow_mut();
+
+        let entries = listings.entry(dir.to_path_buf()).or_insert_with(|| {
+            fs::read_dir(dir)
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| {
+                            let entry = entry.ok()?;
+                            let is_dir = entry.path().is_dir();
+
+                            Some((entry.path(), is_dir))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+
+        let lowercase = name.to_lowercase();
+
+        let matches = |(path, entry_is_dir): &&(PathBuf, bool), exact: bool| {
+            let Some(entry_name) = path.file_name().and_then(|name| name.to_str()) else {
+                return false;
+            };
+
+            let same_name = if exact {
+                entry_name == name
+            } else {
+                entry_name.to_lowercase() == lowercase
+            };
+
+            same_name && *entry_is_dir == is_dir
+        };
+
+        entries
+            .iter()
+            .find(|entry| matches(entry, true))
+            .or_else(|| entries.iter().find(|entry| matches(entry, false)))
+            .map(|(path, _)| path.clone())
+    }
+}
+
+/// Sorts the files of a song folder by whether the charts refer to them. Charts are left out.
+///
+/// Resources are found with a [`FileResolver`], and subfolders are only scanned if a
+/// resource is in them.
+pub fn classify_files(folder: &Path, charts: &[&BMSFile]) -> Result<Vec<FileUsage>, BmsError> {
+    let resolver = FileResolver::new(folder);
+
+    let references: HashSet<PathBuf> = charts
+        .iter()
+        .flat_map(|chart| chart.resources())
+        .filter_map(|resource| resolver.find(&resource.file))
+        .collect();
+
+    // Every folder that a resource is in, such as `sounds` for `sounds/kick.wav`
+    let referenced_dirs: HashSet<String> = references
+        .iter()
+        .filter_map(|reference| reference.strip_prefix(folder).ok())
+        .flat_map(|relative| relative.ancestors().skip(1))
+        .map(|dir| normalise(&dir.to_string_lossy()))
+        .collect();
+
+    let mut files = Vec::new();
+    let mut dirs = vec![(folder.to_path_buf(), String::new())];
+
+    while let Some((dir, relative_dir)) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !relative_dir.is_empty() => continue,
+            Err(e) => return Err(BmsError::io(&dir, e)),
+        };
+
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let relative = normalise(&format!("{}{}", relative_dir, name));
+
+            if path.is_dir() {
+                if name != WORKING_DIR && referenced_dirs.contains(&relative) {
+                    dirs.push((path.clone(), format!("{}/", relative)));
+                }
+
+                continue;
+            }
+
+            if is_chart(&path) {
+                continue;
+            }
+
+            let kind = ResourceKind::from_path(&path);
+
+            let status = if references.contains(&path) {
+                FileStatus::Referenced
+            } else if kind.is_some() {
+                FileStatus::Unreferenced
+            } else {
+                FileStatus::Unknown
+            };
+
+            files.push(FileUsage { path, kind, status });
+        }
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(files)
+}
+
+/// Writes a short silent WAV file to stand in for missing audio, unless the file already
+/// exists.
+pub fn write_silent_placeholder(path: &Path) -> Result<(), BmsError> {
+    if path.exists() {
+        return Ok(());
+    }
+
+    // 10ms of 16-bit mono silence, as some players refuse empty files
+    const SAMPLE_RATE: u32 = 44100;
+    const SAMPLES: u32 = SAMPLE_RATE / 100;
+
+    let data_size = SAMPLES * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 1 channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    // 2 bytes per frame, 16 bits per sample
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    wav.resize(44 + data_size as usize, 0);
+
+    fs::write(path, wav).map_err(|e| BmsError::io(path, e))
+}
+
+/// Normalises a resource path so that it can be compared with the files in a folder.
+pub(crate) fn normalise(path: &str) -> String {
+    let path = path.trim().replace('\\', "/").to_lowercase();
+
+    path.strip_prefix("./").map(str::to_string).unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TempFolder;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_like_players() {
+        let folder = TempFolder::new("resolve");
+        fs::create_dir_all(folder.join("Sub")).unwrap();
+
+        for file in [
+            "piano01.ogg",
+            "Sub/Piano.WAV",
+            "both.wav",
+            "both.ogg",
+            "title.png",
+        ] {
+            fs::write(folder.join(file), b"").unwrap();
+        }
+
+        let resolver = FileResolver::new(&folder);
+
+        // Another audio extension, a backslash and a different case
+        assert_eq!(
+            resolver.find("piano01.wav"),
+            Some(folder.join("piano01.ogg"))
+        );
+        assert_eq!(
+            resolver.find("sub\\piano.wav"),
+            Some(folder.join("Sub/Piano.WAV"))
+        );
+
+        // The file as written wins over the fallbacks
+        assert_eq!(resolver.find("both.ogg"), Some(folder.join("both.ogg")));
+        assert_eq!(resolver.find("both.flac"), Some(folder.join("both.wav")));
+
+        // Only audio falls back to other extensions
+        assert_eq!(resolver.find("title.bmp"), None);
+        assert_eq!(resolver.find("missing.wav"), None);
+        assert_eq!(
+            resolver.resolve("sounds\\missing.wav"),
+            folder.join("sounds/missing.wav")
+        );
+    }
+
+    #[test]
+    fn test_classify_files() {
+        let folder = TempFolder::new("resource");
+
+        for dir in ["Sounds", "unrelated", ".bmsjoin"] {
+            fs::create_dir_all(folder.join(dir)).unwrap();
+        }
+
+        let chart = folder.join("chart.bms");
+        fs::write(
+            &chart,
+            "#STAGEFILE title.PNG\n#bmp01 bga.mpg\n#WAV01 sounds\\KICK.wav\n#WAV02 snare.flac\n",
+        )
+        .unwrap();
+
+        for file in [
+            "title.png",
+            "bga.mpg",
+            "Sounds/kick.WAV",
+            "Sounds/hat.mp3",
+            "snare.flac",
+            "unused.AIFF",
+            "readme.txt",
+            "unrelated/other.wav",
+            ".bmsjoin/old.wav",
+        ] {
+            fs::write(folder.join(file), b"").unwrap();
+        }
+
+        let bms = BMSFile::from_path(&chart).unwrap();
+        let files = classify_files(&folder, &[&bms]).unwrap();
+
+        let status = |file: &str| {
+            files
+                .iter()
+                .find(|usage| usage.path == folder.join(file))
+                .map(|usage| usage.status)
+        };
+
+        assert_eq!(status("title.png"), Some(FileStatus::Referenced));
+        assert_eq!(status("bga.mpg"), Some(FileStatus::Referenced));
+        assert_eq!(status("Sounds/kick.WAV"), Some(FileStatus::Referenced));
+        assert_eq!(status("snare.flac"), Some(FileStatus::Referenced));
+        assert_eq!(status("Sounds/hat.mp3"), Some(FileStatus::Unreferenced));
+        assert_eq!(status("unused.AIFF"), Some(FileStatus::Unreferenced));
+        assert_eq!(status("readme.txt"), Some(FileStatus::Unknown));
+
+        // Folders no resource goes through are left alone, as is the chart
+        assert_eq!(status("unrelated/other.wav"), None);
+        assert_eq!(status(".bmsjoin/old.wav"), None);
+        assert_eq!(status("chart.bms"), None);
+        assert_eq!(files.len(), 7);
+    }
+}